@@ -0,0 +1,155 @@
+//! BASIC's BSAVE memory dumps (.BSV/.PIC), the usual way CGA and EGA screens were saved.
+//! A 7 byte header (0xFD, segment, offset and length as little endian u16s) precedes the data,
+//! which is often followed by an 0x1A EOF byte
+
+use std::path::Path;
+
+use crate::detect::format::Magic;
+use crate::file_data::Raw;
+use crate::image::Image;
+use crate::parser::ParserType;
+use crate::ImageType;
+
+pub const MAGIC: u8 = 0xFD;
+pub const HEADER_LEN: usize = 7;
+pub const CGA_SEGMENT: u16 = 0xB800;
+pub const EGA_SEGMENT: u16 = 0xA000;
+pub const EXTENSIONS: [&str; 3] = ["bsv", "pic", "bsave"];
+const EOF: u8 = 0x1A;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub segment: u16,
+    pub offset: u16,
+    pub length: u16,
+}
+
+impl Header {
+    pub fn read(bytes: &[u8]) -> Option<Self> {
+        if Magic::sniff(bytes)? != Magic::Bsave {
+            return None;
+        }
+        let word = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        Some(Self {
+            segment: word(1),
+            offset: word(3),
+            length: word(5),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let [s0, s1] = self.segment.to_le_bytes();
+        let [o0, o1] = self.offset.to_le_bytes();
+        let [l0, l1] = self.length.to_le_bytes();
+        [MAGIC, s0, s1, o0, o1, l0, l1]
+    }
+
+    /// The parser and width of the screen mode the length suggests: a CGA screen is two
+    /// interlaced 8k banks, a 320x200 EGA screen its four planes one after another.
+    /// Anything else is assumed to be a linear dump a screen wide
+    pub fn layout(&self) -> (ParserType, usize) {
+        match self.length {
+            16000..=16384 => (ParserType::CGAInterlaced, 320),
+            32000..=32768 => (ParserType::EGAPlanar, 320),
+            _ if self.segment == EGA_SEGMENT => (ParserType::EGAPlanar, 320),
+            _ => (ParserType::CGA, 320),
+        }
+    }
+}
+
+/// Strip the header and decode with the screen mode it implies
+pub fn decode(raw: &Raw) -> Result<Image, String> {
+    let header = Header::read(raw.bytes()).ok_or("not a BSAVE file")?;
+    let (parser, width) = header.layout();
+    raw.parse_range(parser, width, HEADER_LEN, Some(header.length as usize))
+}
+
+pub fn is_bsave_path(path: &Path) -> bool {
+    crate::has_extension(path, &EXTENSIONS)
+}
+
+/// A file that BLOADs back into video memory. Screen-wide images are laid out as the
+/// screen is (see [Header::layout]); anything narrower keeps the image's own encoding
+pub fn encode(image: &Image) -> Result<Vec<u8>, String> {
+    let data = image.data();
+    let screen = data.width() == Image::MAX_WIDTH && data.height() <= 200;
+    let (parser, segment) = match image.image_type() {
+        ImageType::CGA if screen => (ParserType::CGAInterlaced, CGA_SEGMENT),
+        ImageType::EGA if screen => (ParserType::EGAPlanar, EGA_SEGMENT),
+        ImageType::CGA => (image.parser.unwrap_or(ParserType::CGA), CGA_SEGMENT),
+        ImageType::EGA => (image.parser.unwrap_or(ParserType::EGAPlanar), EGA_SEGMENT),
+    };
+    let body = parser.to_bytes(data);
+    let length = u16::try_from(body.len())
+        .map_err(|_| format!("{} bytes is too big for BSAVE", body.len()))?;
+    let header = Header {
+        segment,
+        offset: 0,
+        length,
+    };
+    Ok([&header.to_bytes()[..], &body, &[EOF]].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grid;
+
+    #[test]
+    fn header() {
+        let header = Header {
+            segment: CGA_SEGMENT,
+            offset: 0,
+            length: 2,
+        };
+        let bytes = [&header.to_bytes()[..], &[0xE4, 0x1B, EOF]].concat();
+        assert_eq!(&bytes[..7], &[0xFD, 0x00, 0xB8, 0, 0, 2, 0]);
+        assert_eq!(Header::read(&bytes), Some(header));
+        assert_eq!(Header::read(&[0xFD, 0, 0xB8]), None);
+    }
+
+    #[test]
+    fn cga_screen_round_trip() {
+        let data = Grid::from_flat((0..64_000).map(|i| (i / 320 % 4) as u8).collect(), 320);
+        let image = Image {
+            parser: Some(ParserType::CGA),
+            ..Image::new(data.clone())
+        };
+        let bytes = encode(&image).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 0x4000 + 1);
+        assert_eq!(Header::read(&bytes).unwrap().segment, CGA_SEGMENT);
+
+        let decoded = decode(&Raw::new(&bytes)).unwrap();
+        assert_eq!(decoded.parser, Some(ParserType::CGAInterlaced));
+        assert_eq!(decoded.data(), &data);
+    }
+
+    #[test]
+    fn short_cga_screen() {
+        //saved without the end of the odd bank, so its last rows are missing
+        let header = Header {
+            segment: CGA_SEGMENT,
+            offset: 0,
+            length: 16000,
+        };
+        let bytes = [&header.to_bytes()[..], &[0x55; 16000], &[EOF]].concat();
+        let decoded = decode(&Raw::new(&bytes)).unwrap();
+        assert_eq!(decoded.data().width(), 320);
+        assert_eq!(decoded.data().height(), 195);
+        assert!(decoded.data().pixels().all(|&p| p == 1));
+    }
+
+    #[test]
+    fn ega_screen_round_trip() {
+        let data = Grid::from_flat((0..64_000).map(|i| (i % 16) as u8).collect(), 320);
+        let image = Image {
+            parser: Some(ParserType::EGARowPlanar),
+            ..Image::new(data.clone())
+        };
+        let bytes = encode(&image).unwrap();
+        assert_eq!(Header::read(&bytes).unwrap().length, 32000);
+        let decoded = decode(&Raw::new(&bytes)).unwrap();
+        assert_eq!(decoded.parser, Some(ParserType::EGAPlanar));
+        assert_eq!(decoded.data(), &data);
+    }
+}
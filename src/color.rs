@@ -1,4 +1,4 @@
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Color {
     Black(bool), // Black - Dark Gray
     Blue(bool),
@@ -56,6 +56,22 @@ impl Color {
             Color::White(false) => 0xAAAAAA,
         }
     }
+
+    /// The closest of the 16 colors to a 24 bit RGB value
+    pub fn nearest(rgb24: u32) -> Color {
+        let channels = |c: u32| [(c >> 16) as u8, (c >> 8) as u8, c as u8];
+        let target = channels(rgb24);
+        palette::EGA0
+            .into_iter()
+            .min_by_key(|color| {
+                channels(color.rgb24())
+                    .iter()
+                    .zip(target)
+                    .map(|(&a, b)| (a as i32 - b as i32).pow(2))
+                    .sum::<i32>()
+            })
+            .unwrap()
+    }
 }
 
 pub mod palette {
@@ -63,6 +79,9 @@ pub mod palette {
 
     use crate::{CGAColorPalette, ColorPalette, EGAColorPalette};
 
+    /// Every name `palette_from_abbr` knows
+    pub const NAMES: [&str; 5] = ["ega", "cga0", "cga0i", "cga1", "cga1i"];
+
     pub fn palette_from_abbr(name: &str) -> ColorPalette {
         match name {
             "ega" => EGA0.to_vec(),
@@ -73,6 +92,12 @@ pub mod palette {
         }
     }
 
+    pub fn abbr(palette: &ColorPalette) -> Option<&'static str> {
+        NAMES
+            .into_iter()
+            .find(|name| palette_from_abbr(name) == *palette)
+    }
+
     pub const CGA0: CGAColorPalette = [
         Color::Black(false),
         Color::Cyan(true),
@@ -126,3 +151,11 @@ fn test_ansi_fg() {
     assert_eq!(Color::Black(true).ansi_fg(), 90);
     assert_eq!(Color::Brown(true).ansi_fg(), 93);
 }
+
+#[cfg(test)]
+#[test]
+fn test_nearest() {
+    assert_eq!(Color::nearest(0x000000), Color::Black(false));
+    assert_eq!(Color::nearest(0xA85400), Color::Brown(false));
+    assert_eq!(Color::nearest(0xF0F0F0), Color::White(true));
+}
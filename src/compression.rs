@@ -0,0 +1,426 @@
+//! Decompression applied to the raw bytes before a parser sees them, and the matching
+//! compression for writing re-encoded data back
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bitvec::prelude::*;
+
+pub trait Codec {
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String>;
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// Runs are `marker, count, value`, anything else is a literal byte (a literal marker is
+/// written as a run of 1). Ultima IV uses a marker of 0x02
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarkerRle {
+    pub marker: u8,
+}
+
+/// A control byte with the high bit set repeats the next byte (its low 7 bits) times,
+/// otherwise that many literal bytes follow it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HighBitRle;
+
+/// Little endian words, with runs written as `tag, count, value`. id Software's RLEW uses 0xABCD
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rlew {
+    pub tag: u16,
+}
+
+/// Ultima V and VI's LZW: a little endian u32 of the decompressed length, then codes of 9 to 12
+/// bits packed from the low bit up. 0x100 resets the dictionary and 0x101 ends the data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lzw;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Rle(MarkerRle),
+    HighBit(HighBitRle),
+    Rlew(Rlew),
+    Lzw(Lzw),
+}
+
+impl Compression {
+    pub const ULTIMA_MARKER: u8 = 0x02;
+    pub const RLEW_TAG: u16 = 0xABCD;
+
+    /// `rle[:marker]`, `highbit`, `rlew[:tag]` or `lzw`, with the marker and tag in hex
+    pub fn type_str(str: &str) -> Result<Compression, String> {
+        let (name, arg) = match str.split_once(':') {
+            Some((name, arg)) => (name, Some(arg.trim_start_matches("0x"))),
+            None => (str, None),
+        };
+        let hex = |arg: &str| {
+            u16::from_str_radix(arg, 16).map_err(|_| format!("invalid hex value: {}", arg))
+        };
+        match (name, arg) {
+            ("rle", None) => Ok(Self::Rle(MarkerRle {
+                marker: Self::ULTIMA_MARKER,
+            })),
+            ("rle", Some(arg)) => Ok(Self::Rle(MarkerRle {
+                marker: u8::try_from(hex(arg)?).map_err(|_| "rle marker must be a byte")?,
+            })),
+            ("highbit", None) => Ok(Self::HighBit(HighBitRle)),
+            ("rlew", None) => Ok(Self::Rlew(Rlew {
+                tag: Self::RLEW_TAG,
+            })),
+            ("rlew", Some(arg)) => Ok(Self::Rlew(Rlew { tag: hex(arg)? })),
+            ("lzw", None) => Ok(Self::Lzw(Lzw)),
+            _ => Err(format!("unknown compression: {}", str)),
+        }
+    }
+
+    fn codec(&self) -> &dyn Codec {
+        match self {
+            Self::Rle(codec) => codec,
+            Self::HighBit(codec) => codec,
+            Self::Rlew(codec) => codec,
+            Self::Lzw(codec) => codec,
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        self.codec().decompress(data)
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        self.codec().compress(data)
+    }
+}
+
+/// The form [Compression::type_str] reads
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Rle(MarkerRle { marker }) => write!(f, "rle:{:02x}", marker),
+            Self::HighBit(_) => write!(f, "highbit"),
+            Self::Rlew(Rlew { tag }) => write!(f, "rlew:{:04x}", tag),
+            Self::Lzw(_) => write!(f, "lzw"),
+        }
+    }
+}
+
+//runs shorter than this are cheaper as literals
+const MIN_RUN: usize = 3;
+
+fn run_length<T: PartialEq>(data: &[T], max: usize) -> usize {
+    data.iter().take(max).take_while(|&x| *x == data[0]).count()
+}
+
+impl Codec for MarkerRle {
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut out = vec![];
+        let mut bytes = data.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte == self.marker {
+                match (bytes.next(), bytes.next()) {
+                    (Some(&count), Some(&value)) => out.extend(vec![value; count as usize]),
+                    _ => return Err("rle data ends in the middle of a run".to_owned()),
+                }
+            } else {
+                out.push(byte);
+            }
+        }
+        Ok(out)
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut i = 0;
+        while i < data.len() {
+            let run = run_length(&data[i..], u8::MAX as usize);
+            if run > MIN_RUN || data[i] == self.marker {
+                out.extend([self.marker, run as u8, data[i]]);
+            } else {
+                out.extend(&data[i..i + run]);
+            }
+            i += run;
+        }
+        out
+    }
+}
+
+impl Codec for HighBitRle {
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut out = vec![];
+        let mut i = 0;
+        while i < data.len() {
+            let count = (data[i] & 0x7F) as usize;
+            if data[i] & 0x80 != 0 {
+                let value = *data
+                    .get(i + 1)
+                    .ok_or("rle data ends in the middle of a run")?;
+                out.extend(vec![value; count]);
+                i += 2;
+            } else {
+                let literal = data
+                    .get(i + 1..i + 1 + count)
+                    .ok_or("rle data ends in the middle of a literal")?;
+                out.extend(literal);
+                i += 1 + count;
+            }
+        }
+        Ok(out)
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut literal_start = 0;
+        let mut i = 0;
+        let flush = |out: &mut Vec<u8>, literal: &[u8]| {
+            for chunk in literal.chunks(0x7F) {
+                out.push(chunk.len() as u8);
+                out.extend(chunk);
+            }
+        };
+        while i < data.len() {
+            let run = run_length(&data[i..], 0x7F);
+            if run >= MIN_RUN {
+                flush(&mut out, &data[literal_start..i]);
+                out.extend([0x80 | run as u8, data[i]]);
+                i += run;
+                literal_start = i;
+            } else {
+                i += run;
+            }
+        }
+        flush(&mut out, &data[literal_start..]);
+        out
+    }
+}
+
+impl Codec for Rlew {
+    /// An odd trailing byte is passed through
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let words: Vec<u16> = data
+            .chunks_exact(2)
+            .map(|w| u16::from_le_bytes([w[0], w[1]]))
+            .collect();
+        let mut out = vec![];
+        let mut i = 0;
+        while i < words.len() {
+            if words[i] == self.tag {
+                let (count, value) = match words.get(i + 1..i + 3) {
+                    Some(&[count, value]) => (count, value),
+                    _ => return Err("rlew data ends in the middle of a run".to_owned()),
+                };
+                for _ in 0..count {
+                    out.extend(value.to_le_bytes());
+                }
+                i += 3;
+            } else {
+                out.extend(words[i].to_le_bytes());
+                i += 1;
+            }
+        }
+        out.extend(data.chunks_exact(2).remainder());
+        Ok(out)
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let words: Vec<u16> = data
+            .chunks_exact(2)
+            .map(|w| u16::from_le_bytes([w[0], w[1]]))
+            .collect();
+        let mut out = vec![];
+        let mut i = 0;
+        while i < words.len() {
+            let run = run_length(&words[i..], u16::MAX as usize);
+            if run > MIN_RUN || words[i] == self.tag {
+                for word in [self.tag, run as u16, words[i]] {
+                    out.extend(word.to_le_bytes());
+                }
+            } else {
+                for word in &words[i..i + run] {
+                    out.extend(word.to_le_bytes());
+                }
+            }
+            i += run;
+        }
+        out.extend(data.chunks_exact(2).remainder());
+        out
+    }
+}
+
+impl Lzw {
+    const RESET: usize = 0x100;
+    const END: usize = 0x101;
+    const FIRST: usize = 0x102;
+    const MIN_BITS: usize = 9;
+    const MAX_BITS: usize = 12;
+
+    /// Codes widen once the dictionary's next entry needs the extra bit
+    fn bits(next: usize) -> usize {
+        (usize::BITS - next.leading_zeros()).clamp(Self::MIN_BITS as u32, Self::MAX_BITS as u32)
+            as usize
+    }
+}
+
+impl Codec for Lzw {
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let len = data
+            .get(..4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+            .ok_or("lzw data is missing its length")?;
+        let bits = data[4..].view_bits::<Lsb0>();
+        //the length comes from the file, so only reserve what a few bytes per code could give
+        let mut out = Vec::with_capacity(len.min(data.len() * 8));
+        let mut dictionary: Vec<Vec<u8>> = vec![];
+        let mut previous: Option<Vec<u8>> = None;
+        let mut position = 0;
+        while out.len() < len {
+            let width = Self::bits(Self::FIRST + dictionary.len());
+            let code: usize = match bits.get(position..position + width) {
+                Some(code) => code.load_le(),
+                None => break,
+            };
+            position += width;
+            let entry = match code {
+                Self::RESET => {
+                    dictionary.clear();
+                    previous = None;
+                    continue;
+                }
+                Self::END => break,
+                0..=0xFF => vec![code as u8],
+                _ => match (dictionary.get(code - Self::FIRST), &previous) {
+                    (Some(entry), _) => entry.clone(),
+                    //the entry being defined by this very code
+                    (None, Some(prev)) if code - Self::FIRST == dictionary.len() => {
+                        [&prev[..], &prev[..1]].concat()
+                    }
+                    _ => return Err(format!("bad lzw code 0x{:x}", code)),
+                },
+            };
+            if let Some(prev) = previous {
+                if Self::FIRST + dictionary.len() < 1 << Self::MAX_BITS {
+                    dictionary.push([&prev[..], &entry[..1]].concat());
+                }
+            }
+            out.extend(&entry);
+            previous = Some(entry);
+        }
+        out.truncate(len);
+        Ok(out)
+    }
+
+    /// The dictionary is reset whenever it fills
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut bits: BitVec<u8, Lsb0> = BitVec::new();
+        let mut dictionary: HashMap<Vec<u8>, usize> = HashMap::new();
+        //codes written since the last reset, which is what the decoder's code width follows
+        let mut written = 0;
+        let emit = |bits: &mut BitVec<u8, Lsb0>, written: &mut usize, code: usize| {
+            let width = Self::bits(Self::FIRST + written.saturating_sub(1));
+            let start = bits.len();
+            bits.resize(start + width, false);
+            bits[start..].store_le(code);
+            *written += 1;
+        };
+        let code = |dictionary: &HashMap<Vec<u8>, usize>, string: &[u8]| match string {
+            [byte] => *byte as usize,
+            _ => dictionary[string],
+        };
+
+        let mut string: Vec<u8> = vec![];
+        for &byte in data {
+            let mut next = string.clone();
+            next.push(byte);
+            if next.len() == 1 || dictionary.contains_key(&next) {
+                string = next;
+                continue;
+            }
+            emit(&mut bits, &mut written, code(&dictionary, &string));
+            dictionary.insert(next, Self::FIRST + dictionary.len());
+            if Self::FIRST + dictionary.len() == 1 << Self::MAX_BITS {
+                emit(&mut bits, &mut written, Self::RESET);
+                dictionary.clear();
+                written = 0;
+            }
+            string = vec![byte];
+        }
+        if !string.is_empty() {
+            emit(&mut bits, &mut written, code(&dictionary, &string));
+        }
+        emit(&mut bits, &mut written, Self::END);
+        [&(data.len() as u32).to_le_bytes()[..], &bits.into_vec()].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::*;
+
+    fn round_trip(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let compressed = compression.compress(data);
+        assert_eq!(compression.decompress(&compressed).unwrap(), data);
+        assert_eq!(
+            Compression::type_str(&compression.to_string()),
+            Ok(compression)
+        );
+        compressed
+    }
+
+    #[test]
+    fn marker() {
+        let rle = Compression::type_str("rle").unwrap();
+        assert_eq!(
+            rle.decompress(&[1, 2, 5, 9, 3]).unwrap(),
+            [1, 9, 9, 9, 9, 9, 3]
+        );
+        assert_eq!(
+            round_trip(rle, &[7, 7, 7, 7, 7, 2, 1]),
+            vec![2, 5, 7, 2, 1, 2, 1]
+        );
+        assert!(rle.decompress(&[1, 2, 5]).is_err());
+        round_trip(Compression::type_str("rle:0xFF").unwrap(), &[0xFF; 300]);
+    }
+
+    #[test]
+    fn high_bit() {
+        let rle = Compression::type_str("highbit").unwrap();
+        assert_eq!(
+            round_trip(rle, &[1, 2, 0, 0, 0, 0, 3]),
+            vec![2, 1, 2, 0x84, 0, 1, 3]
+        );
+        round_trip(rle, &(0..=255).collect::<Vec<u8>>());
+        assert!(rle.decompress(&[3, 1]).is_err());
+    }
+
+    #[test]
+    fn rlew() {
+        let rlew = Compression::type_str("rlew").unwrap();
+        let data = [1, 0, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 0xCD, 0xAB, 9];
+        assert_eq!(
+            round_trip(rlew, &data),
+            vec![1, 0, 0xCD, 0xAB, 5, 0, 5, 5, 0xCD, 0xAB, 1, 0, 0xCD, 0xAB, 9]
+        );
+        assert_eq!(
+            Compression::type_str("rlew:1234").unwrap().to_string(),
+            "rlew:1234"
+        );
+        assert!(Compression::type_str("lz").is_err());
+    }
+
+    #[test]
+    fn lzw() {
+        let lzw = Compression::type_str("lzw").unwrap();
+        assert_eq!(&round_trip(lzw, b"ababababa")[..4], &[9, 0, 0, 0]);
+        //A, then 0x102 (AA) as it is being defined, then the end code
+        assert_eq!(
+            round_trip(lzw, b"AAA"),
+            vec![3, 0, 0, 0, 0x41, 0x04, 0x06, 0x04]
+        );
+
+        //long enough to widen the codes to 12 bits and fill the dictionary
+        let data: Vec<u8> = (0..60_000u32).map(|i| (i * i / 7 % 251) as u8).collect();
+        let compressed = round_trip(lzw, &data);
+        assert!(compressed.len() < data.len());
+        assert!(lzw.decompress(&[1, 0]).is_err());
+        //a length claiming 4GB doesn't get reserved up front
+        let huge = lzw.decompress(&[0xFF, 0xFF, 0xFF, 0xFF, 0x41, 0x04, 0x06, 0x04]);
+        assert_eq!(huge.unwrap(), b"AAA");
+    }
+}
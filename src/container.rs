@@ -0,0 +1,283 @@
+//! Files holding many variably sized images, located through a table of offsets and/or sizes
+
+use crate::file_data::Raw;
+use crate::image::Image;
+use crate::parser::ParserType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Int {
+    U8,
+    U16,
+    U32,
+}
+
+impl Int {
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U32 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// What offsets are counted from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Base {
+    /// The start of the file
+    Absolute,
+    /// The end of the table
+    Relative,
+}
+
+/// One integer in a table entry or sprite header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Offset,
+    /// Byte length of the image data
+    Size,
+    /// In pixels
+    Width,
+    /// In pixels
+    Height,
+    Skip,
+}
+
+/// Where the sub-images of a file are and how big each one is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    /// Where the table starts
+    pub start: usize,
+    /// None works out the count from the first offset, for tables that run up to the first image
+    pub count: Option<usize>,
+    pub int: Int,
+    pub endian: Endian,
+    pub base: Base,
+    /// The fields of each table entry, in order
+    pub fields: Vec<Field>,
+    /// Fields at the start of each image's data, which are skipped before decoding it
+    pub header: Vec<Field>,
+}
+
+/// The location and dimensions of one sub-image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub offset: usize,
+    pub length: usize,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+}
+
+#[derive(Default)]
+struct Values {
+    offset: Option<usize>,
+    size: Option<usize>,
+    width: Option<usize>,
+    height: Option<usize>,
+}
+
+impl Table {
+    /// A table of `count` absolute offsets at the start of the file
+    pub fn offsets(int: Int, endian: Endian, count: Option<usize>) -> Self {
+        Self {
+            start: 0,
+            count,
+            int,
+            endian,
+            base: Base::Absolute,
+            fields: vec![Field::Offset],
+            header: vec![],
+        }
+    }
+
+    fn entry_size(&self) -> usize {
+        self.fields.len() * self.int.size()
+    }
+
+    fn read(&self, bytes: &[u8], at: usize) -> Result<usize, String> {
+        let size = self.int.size();
+        let field = bytes
+            .get(at..at + size)
+            .ok_or(format!("table reads past the end of the file at {:#x}", at))?;
+        let fold = |acc: usize, byte: &u8| acc << 8 | *byte as usize;
+        Ok(match self.endian {
+            Endian::Big => field.iter().fold(0, fold),
+            Endian::Little => field.iter().rev().fold(0, fold),
+        })
+    }
+
+    fn read_fields(&self, bytes: &[u8], at: usize, fields: &[Field]) -> Result<Values, String> {
+        let mut values = Values::default();
+        for (i, field) in fields.iter().enumerate() {
+            let value = self.read(bytes, at + i * self.int.size())?;
+            match field {
+                Field::Offset => values.offset = Some(value),
+                Field::Size => values.size = Some(value),
+                Field::Width => values.width = Some(value),
+                Field::Height => values.height = Some(value),
+                Field::Skip => {}
+            }
+        }
+        Ok(values)
+    }
+
+    fn count(&self, bytes: &[u8]) -> Result<usize, String> {
+        if let Some(count) = self.count {
+            return Ok(count);
+        }
+        let first = self.read_fields(bytes, self.start, &self.fields)?;
+        let offset = first
+            .offset
+            .ok_or("a table without offsets needs a count")?;
+        let table_len = match self.base {
+            Base::Absolute => offset.saturating_sub(self.start),
+            Base::Relative => 0,
+        };
+        if table_len == 0 || self.entry_size() == 0 {
+            return Err("can't work out the table length from the first offset".to_owned());
+        }
+        Ok(table_len / self.entry_size())
+    }
+
+    /// Locate every sub-image. Without sizes, each image runs up to the next one (or the end of
+    /// the file); without offsets, they follow one another from the end of the table
+    pub fn entries(&self, raw: &Raw) -> Result<Vec<Entry>, String> {
+        let bytes = raw.bytes();
+        let count = self.count(bytes)?;
+        let table_end = self.start + count * self.entry_size();
+        let values = (0..count)
+            .map(|i| self.read_fields(bytes, self.start + i * self.entry_size(), &self.fields))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let base = match self.base {
+            Base::Absolute => 0,
+            Base::Relative => table_end,
+        };
+        let mut offsets = Vec::with_capacity(count);
+        let mut next = table_end;
+        for v in &values {
+            let offset = v.offset.map_or(next, |o| base + o);
+            next = offset + v.size.unwrap_or(0);
+            offsets.push(offset);
+        }
+
+        let mut entries = Vec::with_capacity(count);
+        for (i, v) in values.iter().enumerate() {
+            let offset = offsets[i];
+            let end = match v.size {
+                Some(size) => offset + size,
+                None => offsets.get(i + 1).copied().unwrap_or(bytes.len()),
+            };
+            if offset > bytes.len() || end > bytes.len() || end < offset {
+                return Err(format!(
+                    "entry {} ({:#x}..{:#x}) is outside the file",
+                    i, offset, end
+                ));
+            }
+            let header = self.read_fields(bytes, offset, &self.header)?;
+            let skip = self.header.len() * self.int.size();
+            entries.push(Entry {
+                offset: offset + skip,
+                length: (end - offset).saturating_sub(skip),
+                width: header.width.or(v.width),
+                height: header.height.or(v.height),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Decode every sub-image, each with its own width (or `width` if the table doesn't have one).
+    /// A known height trims any padding after the image
+    pub fn decode(
+        &self,
+        raw: &Raw,
+        parser: ParserType,
+        width: usize,
+    ) -> Result<Vec<Image>, String> {
+        let bits = parser.image_type().word_size();
+        self.entries(raw)?
+            .into_iter()
+            .map(|entry| {
+                let width = entry.width.unwrap_or(width);
+                let length = entry.height.map_or(entry.length, |height| {
+                    entry.length.min((width * height * bits).div_ceil(8))
+                });
+                raw.parse_range(parser, width, entry.offset, Some(length))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_table() {
+        //two u16 offsets, then a 4x2 and a 4x1 cga image
+        let raw = Raw::new(&[4, 0, 6, 0, 0b00011011, 0b11100100, 0xFF]);
+        let table = Table::offsets(Int::U16, Endian::Little, None);
+        assert_eq!(
+            table.entries(&raw).unwrap(),
+            vec![
+                Entry {
+                    offset: 4,
+                    length: 2,
+                    width: None,
+                    height: None
+                },
+                Entry {
+                    offset: 6,
+                    length: 1,
+                    width: None,
+                    height: None
+                }
+            ]
+        );
+        let images = table.decode(&raw, ParserType::CGA, 4).unwrap();
+        assert_eq!(
+            images[0].data().to_vecs(),
+            vec![vec![0, 1, 2, 3], vec![3, 2, 1, 0]]
+        );
+        assert_eq!(images[1].data().to_vecs(), vec![vec![3, 3, 3, 3]]);
+    }
+
+    #[test]
+    fn sizes_and_dimensions() {
+        //big endian sizes, with each image starting with its width and height
+        let raw = Raw::new(&[
+            0, 6, 0, 5, //
+            0, 8, 0, 1, 0x1B, 0xE4, //
+            0, 4, 0, 1, 0xFF,
+        ]);
+        let table = Table {
+            start: 0,
+            count: Some(2),
+            int: Int::U16,
+            endian: Endian::Big,
+            base: Base::Relative,
+            fields: vec![Field::Size],
+            header: vec![Field::Width, Field::Height],
+        };
+        let images = table.decode(&raw, ParserType::CGA, 320).unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(
+            images[0].data().to_vecs(),
+            vec![vec![0, 1, 2, 3, 3, 2, 1, 0]]
+        );
+        assert_eq!(images[1].data().to_vecs(), vec![vec![3, 3, 3, 3]]);
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let raw = Raw::new(&[2, 0, 99, 0]);
+        let table = Table::offsets(Int::U16, Endian::Little, Some(2));
+        assert!(table.entries(&raw).is_err());
+    }
+}
@@ -0,0 +1,383 @@
+//! Format descriptions: a TOML file saying how a format stores its pixels, compiled into a
+//! parser at runtime so new formats can be added and shared without recompiling.
+//! See `formats/` for examples
+//!
+//! ```toml
+//! name = "ultima4_tiles"
+//! bits_per_pixel = 4
+//! layout = "packed"
+//! width = 16
+//! tile_height = 16
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::color::palette;
+use crate::compression::Compression;
+use crate::file_data::Raw;
+use crate::image::{Image, TileOptions};
+use crate::parser::ProcessBinary;
+use crate::{ColorPalette, Grid, ImageType, RawGrid};
+
+pub const EXTENSION: &str = "toml";
+
+/// How the bits of each pixel are grouped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    /// Each pixel's bits together, several pixels to a byte
+    #[default]
+    Packed,
+    /// One bit of every pixel per plane, whole planes one after another
+    Planar,
+    /// Each row's planes in turn
+    RowPlanar,
+}
+
+/// Which end of a byte holds the first pixel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BitOrder {
+    #[default]
+    Msb,
+    Lsb,
+}
+
+impl BitOrder {
+    fn shift(self, i: usize) -> usize {
+        match self {
+            Self::Msb => 7 - i % 8,
+            Self::Lsb => i % 8,
+        }
+    }
+
+    fn get(self, bytes: &[u8], i: usize) -> bool {
+        bytes[i / 8] >> self.shift(i) & 1 == 1
+    }
+
+    fn set(self, bytes: &mut [u8], i: usize) {
+        bytes[i / 8] |= 1 << self.shift(i);
+    }
+}
+
+/// Tiles are decoded as a strip `width` pixels wide and cut every `tile_height` rows, as with
+/// `-t`. The header is skipped and the rest decompressed before the pixels are read
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Description {
+    pub name: String,
+    pub bits_per_pixel: usize,
+    #[serde(default)]
+    pub layout: Layout,
+    /// The pixel bit each stored plane holds, in the order stored [default: highest first]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plane_order: Option<Vec<usize>>,
+    #[serde(default)]
+    pub bit_order: BitOrder,
+    #[serde(default)]
+    pub header: usize,
+    pub width: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tile_height: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palette: Option<String>,
+}
+
+impl Description {
+    pub fn parse(toml: &str) -> Result<Self, String> {
+        let description: Self = toml::from_str(toml).map_err(|e| e.to_string())?;
+        description.validate()?;
+        Ok(description)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        Self::parse(&fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("descriptions are plain tables")
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let bpp = self.bits_per_pixel;
+        let valid_bpp = match self.layout {
+            Layout::Packed => matches!(bpp, 1 | 2 | 4),
+            Layout::Planar | Layout::RowPlanar => (1..=4).contains(&bpp),
+        };
+        if !valid_bpp {
+            return Err(format!(
+                "{}: {} bits per pixel can't be {:?}",
+                self.name, bpp, self.layout
+            ));
+        }
+        if self.width == 0 || self.tile_height == Some(0) {
+            return Err(format!("{}: width and tile height can't be 0", self.name));
+        }
+        if let Some(order) = &self.plane_order {
+            let mut sorted = order.clone();
+            sorted.sort();
+            if sorted != (0..bpp).collect::<Vec<_>>() {
+                return Err(format!(
+                    "{}: plane order must list each of the {} bits once",
+                    self.name, bpp
+                ));
+            }
+        }
+        if let Some(palette) = &self.palette {
+            if !palette::NAMES.contains(&palette.as_str()) {
+                return Err(format!("{}: unknown palette {}", self.name, palette));
+            }
+        }
+        self.compression()?;
+        Ok(())
+    }
+
+    pub fn compression(&self) -> Result<Option<Compression>, String> {
+        self.compression
+            .as_deref()
+            .map(Compression::type_str)
+            .transpose()
+    }
+
+    fn plane_order(&self) -> Vec<usize> {
+        self.plane_order
+            .clone()
+            .unwrap_or_else(|| (0..self.bits_per_pixel).rev().collect())
+    }
+
+    pub fn palette(&self) -> ColorPalette {
+        match &self.palette {
+            Some(name) => palette::palette_from_abbr(name),
+            None => match self.image_type() {
+                ImageType::EGA => palette::EGA0.to_vec(),
+                ImageType::CGA => palette::CGA1.to_vec(),
+            },
+        }
+    }
+
+    pub fn decode(&self, raw: &Raw) -> Result<Image, String> {
+        self.decode_with(raw, TileOptions::default())
+    }
+
+    /// Decodes with the tiles laid out as `options` say, as [Image::tile_with]
+    pub fn decode_with(&self, raw: &Raw, options: TileOptions) -> Result<Image, String> {
+        let data = raw.slice(self.header, None);
+        let compression = self.compression()?;
+        let bytes = match compression {
+            Some(compression) => compression.decompress(data.bytes())?,
+            None => data.bytes().to_vec(),
+        };
+        let image = Image {
+            offset: self.header,
+            compression,
+            palette: Some(self.palette()),
+            ..Image::new(self.process_input(&bytes, self.width))
+        };
+        Ok(match self.tile_height {
+            Some(tile_height) => image.tile_with(tile_height, options),
+            None => image,
+        })
+    }
+
+    /// The file contents for the pixels, after a zeroed header
+    pub fn encode(&self, image_data: &RawGrid) -> Result<Vec<u8>, String> {
+        let bytes = self.to_bytes(image_data);
+        let body = match self.compression()? {
+            Some(compression) => compression.compress(&bytes),
+            None => bytes,
+        };
+        Ok([vec![0; self.header], body].concat())
+    }
+
+    fn to_bytes(&self, image_data: &RawGrid) -> Vec<u8> {
+        let pixels: Vec<u8> = image_data.pixels().copied().collect();
+        match self.layout {
+            Layout::Packed => {
+                let bpp = self.bits_per_pixel;
+                let mut bytes = vec![0; (pixels.len() * bpp).div_ceil(8)];
+                for (p, pixel) in pixels.iter().enumerate() {
+                    for b in 0..bpp {
+                        if pixel >> self.value_bit(b) & 1 == 1 {
+                            self.bit_order.set(&mut bytes, p * bpp + b);
+                        }
+                    }
+                }
+                bytes
+            }
+            Layout::Planar => self.planes(&pixels),
+            Layout::RowPlanar => image_data.rows().flat_map(|row| self.planes(row)).collect(),
+        }
+    }
+
+    /// The bit of a pixel's value held by its `b`th stored bit when packed
+    fn value_bit(&self, b: usize) -> usize {
+        match self.bit_order {
+            BitOrder::Msb => self.bits_per_pixel - 1 - b,
+            BitOrder::Lsb => b,
+        }
+    }
+
+    fn planes(&self, pixels: &[u8]) -> Vec<u8> {
+        let plane_len = pixels.len().div_ceil(8);
+        let mut bytes = vec![0; plane_len * self.bits_per_pixel];
+        for (i, bit) in self.plane_order().into_iter().enumerate() {
+            let plane = &mut bytes[i * plane_len..(i + 1) * plane_len];
+            for (p, pixel) in pixels.iter().enumerate() {
+                if pixel >> bit & 1 == 1 {
+                    self.bit_order.set(plane, p);
+                }
+            }
+        }
+        bytes
+    }
+
+    fn unplane(&self, bytes: &[u8], pixels: &mut [u8]) {
+        let plane_len = bytes.len() / self.bits_per_pixel;
+        for (plane, bit) in bytes.chunks_exact(plane_len.max(1)).zip(self.plane_order()) {
+            for (p, pixel) in pixels.iter_mut().enumerate().take(plane_len * 8) {
+                *pixel |= (self.bit_order.get(plane, p) as u8) << bit;
+            }
+        }
+    }
+}
+
+impl ProcessBinary for Description {
+    fn image_type(&self) -> ImageType {
+        if self.bits_per_pixel <= 2 {
+            ImageType::CGA
+        } else {
+            ImageType::EGA
+        }
+    }
+
+    fn process_input(&self, buffer: &[u8], width: usize) -> RawGrid {
+        let bpp = self.bits_per_pixel;
+        let pixels = match self.layout {
+            Layout::Packed => (0..buffer.len() * 8 / bpp)
+                .map(|p| {
+                    (0..bpp).fold(0, |value, b| {
+                        value | (self.bit_order.get(buffer, p * bpp + b) as u8) << self.value_bit(b)
+                    })
+                })
+                .collect(),
+            Layout::Planar => {
+                let mut pixels = vec![0; buffer.len() / bpp * 8];
+                self.unplane(buffer, &mut pixels);
+                pixels
+            }
+            Layout::RowPlanar => {
+                let row_len = width.div_ceil(8) * bpp;
+                buffer
+                    .chunks(row_len.max(1))
+                    .flat_map(|row| {
+                        //a partial last row is padded out, as the built in row planar parser does
+                        let mut row = row.to_vec();
+                        row.resize(row_len, 0);
+                        let mut pixels = vec![0; width.div_ceil(8) * 8];
+                        self.unplane(&row, &mut pixels);
+                        pixels.truncate(width);
+                        pixels
+                    })
+                    .collect()
+            }
+        };
+        Grid::from_flat(pixels, width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{EGARowPlanar, ParserType};
+
+    fn description(toml: &str) -> Description {
+        Description::parse(&format!("name = \"test\"\nwidth = 16\n{}", toml)).unwrap()
+    }
+
+    #[test]
+    fn matches_built_in_parsers() {
+        let data = Grid::from_flat((0..64).map(|i| (i * 7 % 16) as u8).collect(), 16);
+        for (toml, parser) in [
+            (
+                "bits_per_pixel = 4\nlayout = \"planar\"",
+                ParserType::EGAPlanar,
+            ),
+            (
+                "bits_per_pixel = 4\nlayout = \"row_planar\"",
+                ParserType::EGARowPlanar,
+            ),
+            ("bits_per_pixel = 4", ParserType::EGAPacked),
+        ] {
+            let description = description(toml);
+            let bytes = parser.to_bytes(&data);
+            assert_eq!(description.to_bytes(&data), bytes, "{}", toml);
+            assert_eq!(description.process_input(&bytes, 16), data, "{}", toml);
+            if parser == ParserType::EGARowPlanar {
+                //a partial last row
+                let cut = &bytes[..bytes.len() - 3];
+                assert_eq!(
+                    description.process_input(cut, 16),
+                    EGARowPlanar.process_input(cut, 16)
+                );
+            }
+        }
+        let cga = Grid::from_flat((0..64).map(|i| (i % 4) as u8).collect(), 16);
+        let description = description("bits_per_pixel = 2");
+        assert!(matches!(description.image_type(), ImageType::CGA));
+        assert_eq!(description.to_bytes(&cga), ParserType::CGA.to_bytes(&cga));
+    }
+
+    #[test]
+    fn orders_and_round_trips() {
+        let data = Grid::from_flat((0..32).map(|i| (i * 3 % 8) as u8).collect(), 16);
+        let lsb = description("bits_per_pixel = 4\nbit_order = \"lsb\"");
+        assert_eq!(lsb.to_bytes(&data)[0], 0x30);
+
+        let planar = description(
+            "bits_per_pixel = 3\nlayout = \"planar\"\nplane_order = [0, 1, 2]\nheader = 2\ncompression = \"rle\"\ntile_height = 1",
+        );
+        let bytes = planar.encode(&data).unwrap();
+        assert_eq!(&bytes[..2], &[0, 0]);
+        let image = planar.decode(&Raw::new(&bytes)).unwrap();
+        assert_eq!(image.width, 16);
+        assert_eq!(
+            image.data().row(0),
+            &data.pixels().copied().collect::<Vec<_>>()[..]
+        );
+
+        let options = TileOptions {
+            columns: Some(1),
+            gutter: 1,
+            ..TileOptions::default()
+        };
+        let sheet = planar.decode_with(&Raw::new(&bytes), options).unwrap();
+        assert_eq!(sheet.data().width(), 16);
+        assert_eq!(sheet.data().height(), 3);
+        assert_eq!(planar.encode(sheet.untiled().data()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn invalid() {
+        let parse = |toml: &str| Description::parse(&format!("name = \"bad\"\n{}", toml));
+        assert!(parse("width = 8\nbits_per_pixel = 3").is_err());
+        assert!(
+            parse("width = 8\nbits_per_pixel = 2\nlayout = \"planar\"\nplane_order = [0, 0]")
+                .is_err()
+        );
+        assert!(parse("width = 8\nbits_per_pixel = 2\ncompression = \"zip\"").is_err());
+        assert!(parse("width = 8\nbits_per_pixel = 2\nunknown = 1").is_err());
+        assert!(parse("bits_per_pixel = 2").is_err());
+    }
+
+    #[test]
+    fn bundled() {
+        let description =
+            Description::parse(include_str!("../formats/ultima4_tiles.toml")).unwrap();
+        assert_eq!(Description::parse(&description.to_toml()), Ok(description));
+    }
+}
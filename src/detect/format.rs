@@ -0,0 +1,179 @@
+use std::fmt;
+
+use crate::detect::width;
+use crate::file_data::Raw;
+use crate::parser::ParserType;
+use crate::registry;
+use crate::{bsave, lbm, pcx};
+
+/// File formats recognizable from their first few bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Magic {
+    Png,
+    Pcx,
+    Bsave,
+    Lbm,
+}
+
+impl Magic {
+    pub fn sniff(bytes: &[u8]) -> Option<Magic> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Magic::Png)
+        } else if lbm::is_supported(bytes) {
+            Some(Magic::Lbm)
+        } else if bytes.len() > pcx::HEADER_LEN
+            && matches!(bytes[1], 0 | 2 | 3 | 4 | 5)
+            && pcx::Header::read(bytes).is_ok()
+        {
+            //only the layouts pcx can decode, not 256 color or 24 bit files
+            Some(Magic::Pcx)
+        } else if bytes.len() >= 7 && bytes[0] == 0xFD {
+            //length is of the data after the 7 byte header, which is often followed by an EOF byte
+            let length = u16::from_le_bytes([bytes[5], bytes[6]]) as usize;
+            ((bytes.len() - 7).abs_diff(length) <= 1).then_some(Magic::Bsave)
+        } else {
+            None
+        }
+    }
+
+    /// The parser that handles this format, if cega has one. BSAVE files are decoded
+    /// with a raw parser after the header, see [bsave::Header::layout]
+    pub fn parser(&self) -> Option<ParserType> {
+        match self {
+            #[cfg(feature = "png")]
+            Magic::Png => Some(ParserType::Png),
+            Magic::Pcx => Some(ParserType::Pcx),
+            Magic::Lbm => Some(ParserType::Lbm),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Detection {
+    pub parser: ParserType,
+    pub width: usize,
+    /// Where the image data starts, after any header
+    pub offset: usize,
+    pub length: Option<usize>,
+    pub confidence: f64,
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.parser.name(), self.width)?;
+        if self.offset > 0 {
+            write!(f, " offset {}", self.offset)?;
+        }
+        write!(f, " ({:.2})", self.confidence)
+    }
+}
+
+/// Likely parsers and widths, best first. A recognized file format is certain,
+/// (built in or registered), otherwise every raw parser and width is scored by how coherent its output is
+pub fn detect(raw: &Raw) -> Vec<Detection> {
+    if let Some(header) = bsave::Header::read(raw.bytes()) {
+        let (parser, width) = header.layout();
+        return vec![Detection {
+            parser,
+            width,
+            offset: bsave::HEADER_LEN,
+            length: Some(header.length as usize),
+            confidence: 1.0,
+        }];
+    }
+    let parser = Magic::sniff(raw.bytes())
+        .and_then(|m| m.parser())
+        .or_else(|| registry::sniff(raw.bytes()));
+    //a file that only looks like a known format is scored like any other
+    if let Some((parser, image)) = parser.and_then(|p| Some((p, raw.parse(p, 0).ok()?))) {
+        return vec![Detection {
+            parser,
+            width: image.data().width(),
+            offset: 0,
+            length: None,
+            confidence: 1.0,
+        }];
+    }
+    width::rank(raw, &registry::raw())
+        .into_iter()
+        .map(|score| Detection {
+            parser: score.parser,
+            width: score.width,
+            offset: 0,
+            length: None,
+            confidence: score.score.clamp(0.0, 1.0),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::palette;
+    use crate::detect::format::{self, Magic};
+    use crate::file_data::Raw;
+    use crate::parser::ParserType;
+    use crate::{lbm, pcx, Grid};
+
+    #[test]
+    fn sniff() {
+        assert_eq!(Magic::sniff(b"\x89PNG\r\n\x1a\n...."), Some(Magic::Png));
+        let ilbm = lbm::encode(&Grid::new(4, 2), &palette::EGA0.to_vec(), &[]);
+        assert_eq!(Magic::sniff(&ilbm), Some(Magic::Lbm));
+        assert_eq!(Magic::sniff(b"FORM\0\0\0\x04ILBM"), None);
+        assert_eq!(
+            Magic::sniff(&[0xFD, 0, 0xB8, 0, 0, 2, 0, 1, 2]),
+            Some(Magic::Bsave)
+        );
+        assert_eq!(Magic::sniff(&[0xFD, 0, 0xB8, 0, 0, 9, 0, 1, 2]), None);
+        let mut pcx = vec![0x0A, 5, 1, 2];
+        pcx.resize(200, 0);
+        pcx[65] = 1;
+        assert_eq!(Magic::sniff(&pcx), Some(Magic::Pcx));
+        assert_eq!(Magic::sniff(&[0; 200]), None);
+    }
+
+    #[test]
+    fn unsupported_pcx() {
+        //a 256 color PCX, whose palette follows the image
+        let mut bytes = vec![0x0A, 5, 1, 8, 0, 0, 0, 0, 15, 0, 0, 0];
+        bytes.resize(pcx::HEADER_LEN, 0);
+        bytes[65] = 1;
+        bytes[66] = 16;
+        bytes.extend([0xC4, 0x07].repeat(240));
+        bytes.push(0x0C);
+        bytes.extend([0; 768]);
+        assert_eq!(Magic::sniff(&bytes), None);
+        let detected = format::detect(&Raw::new(&bytes));
+        assert!(detected.iter().all(|d| ParserType::RAW.contains(&d.parser)));
+        assert!(Raw::new(&bytes).parse(ParserType::Pcx, 0).is_err());
+    }
+
+    #[test]
+    fn detects_png() {
+        let bytes = std::fs::read("test.png").unwrap();
+        let detected = format::detect(&Raw::new(&bytes));
+        assert_eq!(detected.len(), 1);
+        assert_eq!(detected[0].parser, ParserType::Png);
+        assert_eq!(detected[0].confidence, 1.0);
+    }
+
+    #[test]
+    fn detects_bsave() {
+        let mut bytes = vec![0xFD, 0x00, 0xB8, 0, 0, 0x00, 0x40];
+        bytes.resize(7 + 0x4000, 0x55);
+        let detected = format::detect(&Raw::new(&bytes));
+        assert_eq!(detected.len(), 1);
+        assert_eq!(
+            (detected[0].parser, detected[0].width, detected[0].offset),
+            (ParserType::CGAInterlaced, 320, 7)
+        );
+    }
+
+    #[test]
+    fn scores_raw_parsers() {
+        let bytes = std::fs::read("test.cga").unwrap();
+        let best = format::detect(&Raw::new(&bytes))[0];
+        assert_eq!((best.parser, best.width), (ParserType::CGA, 16));
+    }
+}
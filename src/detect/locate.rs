@@ -0,0 +1,130 @@
+//! Finding a tileset's tiles in a bigger image, wherever they are. A map drawn with the tileset
+//! shows them on a grid, which is what checking a guess at a map format needs
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::tilemap::{Flip, Tileset};
+use crate::RawGrid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Found {
+    pub tile: usize,
+    pub x: usize,
+    pub y: usize,
+    pub flip: Flip,
+}
+
+impl fmt::Display for Found {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tile {} at {},{}", self.tile, self.x, self.y)?;
+        match (self.flip.horizontal, self.flip.vertical) {
+            (true, true) => write!(f, " flipped both ways"),
+            (true, false) => write!(f, " flipped horizontally"),
+            (false, true) => write!(f, " flipped vertically"),
+            (false, false) => Ok(()),
+        }
+    }
+}
+
+impl Found {
+    /// On the grid a map of these tiles would be drawn on
+    pub fn is_aligned(&self, tileset: &Tileset) -> bool {
+        self.x.is_multiple_of(tileset.tile_width.max(1))
+            && self.y.is_multiple_of(tileset.tile_height.max(1))
+    }
+}
+
+/// Every place a tile (or, with `flips`, a mirror image of one) appears, top to bottom then left
+/// to right. Tiles of one color would match all over any plain area, so they aren't looked for
+pub fn locate(tileset: &Tileset, grid: &RawGrid, flips: bool) -> Vec<Found> {
+    let (tile_width, tile_height) = (tileset.tile_width, tileset.tile_height);
+    if tile_width == 0
+        || tile_height == 0
+        || tile_width > grid.width()
+        || tile_height > grid.height()
+    {
+        return vec![];
+    }
+    let variants = if flips {
+        &Flip::ALL[..]
+    } else {
+        &Flip::ALL[..1]
+    };
+    //by their top row, so most places are ruled out with one lookup
+    let mut candidates: HashMap<Vec<u8>, Vec<(usize, Flip, RawGrid)>> = HashMap::new();
+    for (i, tile) in tileset.tiles.iter().enumerate() {
+        if is_plain(tile) {
+            continue;
+        }
+        let mut looks: Vec<RawGrid> = vec![];
+        for flip in variants {
+            let look = flip.apply(tile);
+            //symmetrical tiles would be found once for each way they look the same
+            if looks.contains(&look) {
+                continue;
+            }
+            looks.push(look.clone());
+            candidates
+                .entry(look.row(0).to_vec())
+                .or_default()
+                .push((i, *flip, look));
+        }
+    }
+
+    let mut found = vec![];
+    for y in 0..=grid.height() - tile_height {
+        let row = grid.row(y);
+        for x in 0..=grid.width() - tile_width {
+            let Some(tiles) = candidates.get(&row[x..x + tile_width]) else {
+                continue;
+            };
+            let window = grid.view(x, y, tile_width, tile_height);
+            for (tile, flip, look) in tiles {
+                if window == look.as_view() {
+                    found.push(Found {
+                        tile: *tile,
+                        x,
+                        y,
+                        flip: *flip,
+                    });
+                }
+            }
+        }
+    }
+    found
+}
+
+fn is_plain(tile: &RawGrid) -> bool {
+    let mut pixels = tile.pixels();
+    let first = pixels.next();
+    pixels.all(|p| Some(p) == first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tilemap::{IndexSize, Tilemap};
+    use crate::Grid;
+
+    #[test]
+    fn locates() {
+        let tiles = Grid::from_flat([[1, 2], [3, 3], [0, 0], [0, 0], [2, 2], [1, 3]].concat(), 2);
+        let tileset = Tileset::from_grid(&tiles, 2, 2);
+        let map = Tilemap::read(&[0, 1, 2, 1, 0, 1], 3, IndexSize::U8).unwrap();
+        let mut image = Grid::new(7, 5);
+        image.blit(&map.render(&tileset).unwrap().as_view(), 1, 1);
+
+        let found = locate(&tileset, &image, false);
+        let places: Vec<_> = found.iter().map(|f| (f.tile, f.x, f.y)).collect();
+        assert_eq!(places, vec![(0, 1, 1), (2, 5, 1), (0, 3, 3)]);
+        assert!(!found[0].is_aligned(&tileset));
+        assert_eq!(found[1].to_string(), "tile 2 at 5,1");
+
+        let mirrored = Flip::new(true, false).apply(&image);
+        let found = locate(&tileset, &mirrored, true);
+        assert_eq!(found.len(), 3);
+        assert!(found.iter().all(|f| f.flip == Flip::new(true, false)));
+        assert!(locate(&tileset, &Grid::new(1, 1), true).is_empty());
+    }
+}
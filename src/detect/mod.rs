@@ -0,0 +1,58 @@
+//! Heuristics for guessing how unknown binary data should be decoded
+
+pub mod format;
+pub mod locate;
+pub mod offset;
+pub mod scan;
+pub mod tiles;
+pub mod width;
+
+use crate::RawGrid;
+
+/// How much more often vertically adjacent pixels match than chance would predict,
+/// given the image's color distribution. 1.0 is perfectly coherent, ~0.0 is noise
+pub fn coherence(grid: &RawGrid) -> f64 {
+    if grid.height() < 2 || grid.width() == 0 {
+        return 0.0;
+    }
+    let pairs = (grid.height() - 1) * grid.width();
+    let matching = grid
+        .rows()
+        .zip(grid.rows().skip(1))
+        .map(|(a, b)| a.iter().zip(b).filter(|(x, y)| x == y).count())
+        .sum::<usize>();
+
+    let chance = chance_of_match(grid);
+    if chance >= 1.0 {
+        return 0.0;
+    }
+    (matching as f64 / pairs as f64 - chance) / (1.0 - chance)
+}
+
+/// Probability two random pixels are the same color
+fn chance_of_match(grid: &RawGrid) -> f64 {
+    let mut counts = [0usize; 256];
+    for pixel in grid.pixels() {
+        counts[*pixel as usize] += 1;
+    }
+    let total = grid.len() as f64;
+    counts.iter().map(|&c| (c as f64 / total).powi(2)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detect::coherence;
+    use crate::Grid;
+
+    #[test]
+    fn coherence_bounds() {
+        let stripes = Grid::from(vec![vec![0, 1, 2, 3]; 4]);
+        assert_eq!(coherence(&stripes), 1.0);
+
+        let checks = Grid::from(vec![vec![0, 1], vec![1, 0], vec![0, 1], vec![1, 0]]);
+        assert!(coherence(&checks) < 0.0);
+
+        let blank = Grid::from(vec![vec![0, 0]; 2]);
+        assert_eq!(coherence(&blank), 0.0);
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+
+use crate::bsave;
+use crate::detect::coherence;
+use crate::detect::format::Magic;
+use crate::file_data::Raw;
+use crate::parser::ParserType;
+use crate::RawGrid;
+
+/// Enough to cover common headers, like BSAVE's 7 bytes
+pub const MAX_OFFSET: usize = 32;
+//offsets a whole number of rows apart decode almost the same, so prefer skipping less
+const OFFSET_PENALTY: f64 = 0.0001;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OffsetScore {
+    pub offset: usize,
+    pub score: f64,
+}
+
+impl fmt::Display for OffsetScore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({:.2})", self.offset, self.score)
+    }
+}
+
+/// The size of a recognized header, which outranks any guess
+pub fn known_header(bytes: &[u8]) -> Option<usize> {
+    match Magic::sniff(bytes)? {
+        Magic::Bsave => Some(bsave::HEADER_LEN),
+        _ => None,
+    }
+}
+
+/// Try each offset up to `max_offset` and rank them by how coherent the decoded image is,
+/// both vertically and in having its row ends fall on real edges.
+/// Images with blank borders (like most sprites) give little to go on horizontally
+pub fn rank(raw: &Raw, parser: ParserType, width: usize, max_offset: usize) -> Vec<OffsetScore> {
+    let header = known_header(raw.bytes());
+    let mut scores: Vec<OffsetScore> = (0..=max_offset.min(raw.bytes().len()))
+        .filter_map(|offset| {
+            let image = raw.parse_range(parser, width, offset, None).ok()?;
+            let known = if header == Some(offset) { 1.0 } else { 0.0 };
+            Some(OffsetScore {
+                offset,
+                score: coherence(image.data()) + seam(image.data()) + known
+                    - offset as f64 * OFFSET_PENALTY,
+            })
+        })
+        .collect();
+    scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+    scores
+}
+
+pub fn best(raw: &Raw, parser: ParserType, width: usize, max_offset: usize) -> Option<OffsetScore> {
+    rank(raw, parser, width, max_offset).into_iter().next()
+}
+
+/// How much more alike horizontal neighbours are than the pixels either side of a row break.
+/// A header shifts the picture's real left edge into the middle of the rows, which scores lower
+fn seam(grid: &RawGrid) -> f64 {
+    if grid.width() < 2 || grid.height() < 2 {
+        return 0.0;
+    }
+    let rows: Vec<&[u8]> = grid.rows().collect();
+    let inside = rows
+        .iter()
+        .map(|row| row.windows(2).filter(|pair| pair[0] == pair[1]).count())
+        .sum::<usize>() as f64
+        / (rows.len() * (grid.width() - 1)) as f64;
+    let across = rows
+        .windows(2)
+        .filter(|pair| pair[0][grid.width() - 1] == pair[1][0])
+        .count() as f64
+        / (rows.len() - 1) as f64;
+    inside - across
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detect::offset;
+    use crate::file_data::Raw;
+    use crate::parser::ParserType;
+    use crate::Grid;
+
+    #[test]
+    fn skips_header() {
+        //a solid left border beside a pattern of blocks
+        let rows = (0..40)
+            .map(|y| {
+                (0..16)
+                    .map(|x| match x {
+                        0 => 3,
+                        _ => ((x / 5 + y / 3) % 3) as u8,
+                    })
+                    .collect()
+            })
+            .collect::<Vec<Vec<u8>>>();
+        //not a valid BSAVE length, so this has to be found from the image
+        let mut bytes = vec![0xFD, 0x00, 0xB8, 0x00, 0x00, 0xA0, 0x00];
+        bytes.extend(ParserType::CGA.to_bytes(&Grid::from(rows)));
+
+        let raw = Raw::new(&bytes);
+        let best = offset::best(&raw, ParserType::CGA, 16, offset::MAX_OFFSET).unwrap();
+        assert_eq!(best.offset, 7);
+    }
+
+    #[test]
+    fn bsave_header() {
+        let mut bytes = vec![0xFD, 0x00, 0xB8, 0x00, 0x00, 0x10, 0x00];
+        bytes.extend([0x1B; 16]);
+        assert_eq!(offset::known_header(&bytes), Some(7));
+        let best = offset::best(&Raw::new(&bytes), ParserType::CGA, 8, 8).unwrap();
+        assert_eq!(best.offset, 7);
+    }
+}
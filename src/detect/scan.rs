@@ -0,0 +1,166 @@
+use std::fmt;
+
+use crate::detect::width::{self, WidthScore};
+use crate::file_data::Raw;
+use crate::registry;
+
+pub const WINDOW: usize = 2048;
+/// Windows below this many bits per byte are padding or blank
+pub const MIN_ENTROPY: f64 = 0.5;
+/// Windows above this many bits per byte are compressed or encrypted, so won't decode directly
+pub const MAX_ENTROPY: f64 = 7.0;
+pub const MIN_COHERENCE: f64 = 0.25;
+
+/// A run of the file that decodes coherently with the parser and width of its best window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub offset: usize,
+    pub length: usize,
+    pub entropy: f64,
+    pub best: WidthScore,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "offset {:#x} length {} entropy {:.1}: {}",
+            self.offset, self.length, self.entropy, self.best
+        )
+    }
+}
+
+/// Shannon entropy in bits per byte, 0.0 for constant data up to 8.0 for uniformly random bytes
+pub fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in bytes {
+        counts[*byte as usize] += 1;
+    }
+    let total = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Slide a `window` byte window across the data `step` bytes at a time. Windows with graphics-like
+/// entropy that some raw parser decodes coherently are merged with any overlapping neighbours
+/// into regions, in file order
+pub fn scan(raw: &Raw, window: usize, step: usize) -> Vec<Region> {
+    let window = window.max(1);
+    let step = step.max(1);
+    let len = raw.bytes().len();
+    let mut regions: Vec<(Region, usize)> = vec![];
+    let parsers = registry::raw();
+    let mut offset = 0;
+    while offset < len {
+        //a short last window is trimmed to whole rows at the narrowest raw widths
+        let mut end = (offset + window).min(len);
+        if end - offset < window {
+            end -= (end - offset) % 8;
+        }
+        if end == offset {
+            break;
+        }
+        let bytes = &raw.bytes()[offset..end];
+        let e = entropy(bytes);
+        if (MIN_ENTROPY..=MAX_ENTROPY).contains(&e) {
+            let best =
+                width::best(&Raw::new(bytes), &parsers).filter(|best| best.score >= MIN_COHERENCE);
+            if let Some(best) = best {
+                match regions.last_mut() {
+                    Some((region, windows)) if offset <= region.offset + region.length => {
+                        region.length = end - region.offset;
+                        region.entropy += e;
+                        *windows += 1;
+                        if best.score > region.best.score {
+                            region.best = best;
+                        }
+                    }
+                    _ => regions.push((
+                        Region {
+                            offset,
+                            length: bytes.len(),
+                            entropy: e,
+                            best,
+                        },
+                        1,
+                    )),
+                }
+            }
+        }
+        if offset + window >= len {
+            break;
+        }
+        offset += step;
+    }
+    regions
+        .into_iter()
+        .map(|(region, windows)| Region {
+            entropy: region.entropy / windows as f64,
+            ..region
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detect::scan;
+    use crate::file_data::Raw;
+    use crate::parser::ParserType;
+    use crate::Grid;
+
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn entropy() {
+        assert_eq!(scan::entropy(&[7; 64]), 0.0);
+        assert_eq!(scan::entropy(&(0..=255).collect::<Vec<u8>>()), 8.0);
+    }
+
+    #[test]
+    fn finds_graphics_in_noise() {
+        //blocky sprites, 64 px (16 bytes) wide
+        let sprites = Grid::from_flat(
+            (0..64 * 256)
+                .map(|i| (((i % 64) / 8 + i / 64 / 16) % 4) as u8)
+                .collect(),
+            64,
+        );
+        let mut bytes = noise(3000, 1);
+        bytes.extend(ParserType::CGA.to_bytes(&sprites));
+        bytes.extend(noise(3000, 2));
+
+        //edges are only as precise as the window
+        let regions = scan::scan(&Raw::new(&bytes), 1024, 256);
+        assert_eq!(regions.len(), 1, "{regions:?}");
+        let region = regions[0];
+        assert!(region.offset.abs_diff(3000) < 1024, "{region}");
+        assert!(
+            (region.offset + region.length).abs_diff(7096) < 1024,
+            "{region}"
+        );
+    }
+
+    #[test]
+    fn odd_length() {
+        let sprites = Grid::from_flat((0..8192).map(|i| (i / 16 % 4) as u8).collect(), 64);
+        let mut bytes = ParserType::CGA.to_bytes(&sprites);
+        bytes.extend([0x1B; 5]);
+        let regions = scan::scan(&Raw::new(&bytes), 1024, 256);
+        let last = regions.last().unwrap();
+        assert_eq!(last.offset + last.length, bytes.len() - 5);
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+
+use crate::RawGrid;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileScore {
+    pub width: usize,
+    pub height: usize,
+    pub score: f64,
+}
+
+impl fmt::Display for TileScore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{} ({:.2})", self.width, self.height, self.score)
+    }
+}
+
+const MIN_TILE: usize = 4;
+const MAX_TILE: usize = 64;
+//sprites often have blank borders, so edges are a weaker signal than repetition
+const EDGE_WEIGHT: f64 = 0.25;
+
+/// Rank likely tile sizes. Tiles show up as rows (and columns) that resemble the ones a tile
+/// further on more than their near neighbours, and as extra change across tile edges.
+/// Tall strips (like those from a narrow -w) are assumed to be a single tile wide
+pub fn rank(grid: &RawGrid) -> Vec<TileScore> {
+    if grid.width() < 2 || grid.height() < 2 {
+        return vec![];
+    }
+    let rows: Vec<&[u8]> = grid.rows().collect();
+    let row_matches = |shift: usize| {
+        matching(
+            rows.iter()
+                .zip(&rows[shift..])
+                .flat_map(|(a, b)| a.iter().zip(*b)),
+        )
+    };
+    let column_matches =
+        |shift: usize| matching(rows.iter().flat_map(|row| row.iter().zip(&row[shift..])));
+
+    //same rule as Image::is_tall
+    let widths = if grid.height() / grid.width() > 4 {
+        vec![(grid.width(), 0.0)]
+    } else {
+        let changes = (0..grid.width() - 1)
+            .map(|x| 1.0 - matching(rows.iter().map(|row| (&row[x], &row[x + 1]))))
+            .collect();
+        periods(column_matches, changes)
+    };
+    let changes = rows
+        .windows(2)
+        .map(|pair| 1.0 - matching(pair[0].iter().zip(pair[1])))
+        .collect();
+    let heights = periods(row_matches, changes);
+
+    let mut scores: Vec<TileScore> = widths
+        .iter()
+        .flat_map(|&(width, sx)| {
+            heights.iter().map(move |&(height, sy)| TileScore {
+                width,
+                height,
+                score: sx + sy,
+            })
+        })
+        .collect();
+    scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+    scores
+}
+
+pub fn best(grid: &RawGrid) -> Option<TileScore> {
+    rank(grid).into_iter().next()
+}
+
+fn matching<'a>(pairs: impl Iterator<Item = (&'a u8, &'a u8)>) -> f64 {
+    let (mut same, mut total) = (0, 0);
+    for (a, b) in pairs {
+        total += 1;
+        if a == b {
+            same += 1;
+        }
+    }
+    same as f64 / total.max(1) as f64
+}
+
+/// Score every period that fits at least twice. `matches(shift)` is the fraction of pixels equal to
+/// the one `shift` along, and `changes[i]` the fraction that differ between line `i` and the next.
+/// A period scores for how much the matches peak over the shifts either side of it, plus how much
+/// more change there is across its edges than inside. Multiples of a period line up just as well,
+/// so each is discounted by its best scoring divisor
+fn periods(matches: impl Fn(usize) -> f64, changes: Vec<f64>) -> Vec<(usize, f64)> {
+    let max = MAX_TILE.min(changes.len().div_ceil(2));
+    if max < MIN_TILE {
+        return vec![];
+    }
+    let matches: Vec<f64> = (0..=max + 1).map(matches).collect();
+    let raw: Vec<(usize, f64)> = (MIN_TILE..=max)
+        .map(|period| {
+            let peak = matches[period] - (matches[period - 1] + matches[period + 1]) / 2.0;
+            let (mut edges, mut inside) = (vec![], vec![]);
+            for (i, change) in changes.iter().enumerate() {
+                if (i + 1) % period == 0 {
+                    edges.push(*change);
+                } else {
+                    inside.push(*change);
+                }
+            }
+            let contrast = (mean(&edges) - mean(&inside)).max(0.0);
+            (period, peak + EDGE_WEIGHT * contrast)
+        })
+        .collect();
+    raw.iter()
+        .map(|&(period, score)| {
+            let harmonic = raw
+                .iter()
+                .filter(|(divisor, _)| *divisor < period && period % divisor == 0)
+                .map(|(_, s)| *s)
+                .fold(0.0, f64::max);
+            (period, score - harmonic)
+        })
+        .collect()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detect::tiles;
+    use crate::{Grid, RawGrid};
+
+    //a diamond in every tile on a blank background
+    fn sprites(columns: usize, rows: usize, tile_width: usize, tile_height: usize) -> RawGrid {
+        let mut grid = Grid::new(columns * tile_width, rows * tile_height);
+        for y in 0..grid.height() {
+            for x in 0..grid.width() {
+                let (dx, dy) = (x % tile_width, y % tile_height);
+                if dx.abs_diff(tile_width / 2) + dy.abs_diff(tile_height / 2) < tile_width / 3 {
+                    grid[(x, y)] = 1;
+                }
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn strip() {
+        let best = tiles::best(&sprites(1, 12, 8, 6)).unwrap();
+        assert_eq!((best.width, best.height), (8, 6));
+    }
+
+    #[test]
+    fn sheet() {
+        let ranked = tiles::rank(&sprites(6, 5, 8, 12));
+        assert_eq!((ranked[0].width, ranked[0].height), (8, 12));
+        assert!(ranked[0].score > ranked[1].score);
+    }
+}
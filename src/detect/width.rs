@@ -0,0 +1,122 @@
+use std::fmt;
+
+use crate::detect::coherence;
+use crate::file_data::Raw;
+use crate::image::Image;
+use crate::parser::ParserType;
+use crate::ImageType;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WidthScore {
+    pub parser: ParserType,
+    pub width: usize,
+    pub score: f64,
+}
+
+impl fmt::Display for WidthScore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({:.2})",
+            self.parser.name(),
+            self.width,
+            self.score
+        )
+    }
+}
+
+/// Widths worth trying: every multiple of 8 up to 320, plus the exact factors of the pixel count
+pub fn candidates(raw: &Raw, parser: ParserType) -> Vec<usize> {
+    let itype = parser.image_type();
+    let pixel_count = raw.pixel_count(itype);
+    let mut widths: Vec<usize> = (8..=Image::MAX_WIDTH).step_by(8).collect();
+    widths.extend(raw.widths(itype).into_iter().map(|w| w as usize));
+    if let ImageType::EGA = itype {
+        //row planar needs whole bytes per plane
+        widths.retain(|w| w % 8 == 0);
+    }
+    widths.retain(|&w| pixel_count / w >= 2);
+    widths.sort_unstable();
+    widths.dedup();
+    widths
+}
+
+/// Score every candidate width for each parser by vertical coherence, best first
+pub fn rank(raw: &Raw, parsers: &[ParserType]) -> Vec<WidthScore> {
+    let mut scores: Vec<WidthScore> = parsers
+        .iter()
+        .flat_map(|&parser| {
+            candidates(raw, parser)
+                .into_iter()
+                .filter_map(move |width| {
+                    Some(WidthScore {
+                        parser,
+                        width,
+                        score: coherence(raw.parse(parser, width).ok()?.data()),
+                    })
+                })
+        })
+        .collect();
+    //stable sort keeps narrower widths first on ties
+    scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+    scores
+}
+
+pub fn best(raw: &Raw, parsers: &[ParserType]) -> Option<WidthScore> {
+    rank(raw, parsers).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detect::width;
+    use crate::file_data::Raw;
+    use crate::parser::ParserType;
+    use crate::Grid;
+
+    //irregular vertical stripes that shift every 8 rows, so they only line up at the right width
+    fn stripes(width: usize, height: usize) -> Raw {
+        let rows = (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| ((x * 7 + x / 5 + y / 8) % 4) as u8)
+                    .collect()
+            })
+            .collect::<Vec<Vec<u8>>>();
+        Raw::new(&ParserType::CGA.to_bytes(&Grid::from(rows)))
+    }
+
+    #[test]
+    fn finds_width() {
+        let raw = stripes(24, 40);
+        let ranked = width::rank(&raw, &[ParserType::CGA]);
+        assert_eq!(ranked[0].width, 24);
+        assert!(ranked[0].score > ranked[1].score);
+        assert_eq!(width::best(&raw, &ParserType::RAW).unwrap().width, 24);
+    }
+
+    #[test]
+    fn partial_last_row() {
+        //not a whole number of 4 byte plane groups, so the last row planar row is cut short
+        let raw = Raw::new(
+            &(0..1001u32)
+                .map(|i| (i * 37 % 251) as u8)
+                .collect::<Vec<_>>(),
+        );
+        let ranked = width::rank(&raw, &ParserType::RAW);
+        assert!(ranked.iter().any(|s| s.parser == ParserType::EGARowPlanar));
+        let image = raw.parse(ParserType::EGARowPlanar, 16).unwrap();
+        assert_eq!(image.data().height(), 126);
+        assert_eq!(image.data().row(125)[8..], [0; 8]);
+    }
+
+    #[test]
+    fn candidates() {
+        let raw = Raw::new(&[0; 100]);
+        let cga = width::candidates(&raw, ParserType::CGA);
+        assert_eq!(cga.first(), Some(&5));
+        assert!(cga.contains(&25));
+        assert!(cga.iter().all(|&w| w <= 200));
+        let ega = width::candidates(&raw, ParserType::EGARowPlanar);
+        assert!(ega.iter().all(|&w| w % 8 == 0 && w <= 100));
+    }
+}
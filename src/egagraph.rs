@@ -0,0 +1,481 @@
+//! id Software's EGA graphics archives (Commander Keen 4-6 and others): EGAGRAPH holds Huffman
+//! compressed chunks, EGAHEAD their offsets and EGADICT the Huffman tree. Modders usually
+//! extract the latter two from the game's executable. Where each kind of chunk starts differs
+//! between games: Keen 4's [Layout] is built in, and others can be loaded from TOML.
+//! https://moddingwiki.shikadi.net/wiki/EGAGRAPH_Format
+
+use crate::color::palette;
+use crate::image::Image;
+use crate::parser::{EGAPlanar, ProcessBinary};
+use crate::{Grid, RawGrid};
+
+const NODES: usize = 255;
+const ROOT: usize = 254;
+const PLANES: usize = 4;
+
+/// Where each kind of chunk starts in a particular game's archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "formats",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct Layout {
+    /// Bytes per EGAHEAD offset
+    pub head_size: usize,
+    pub pic_table: usize,
+    pub picm_table: usize,
+    pub sprite_table: usize,
+    pub start_pics: usize,
+    pub start_picm: usize,
+    pub start_sprites: usize,
+    pub start_tile8: usize,
+    pub start_tile8m: usize,
+    pub start_tile16: usize,
+    pub start_tile16m: usize,
+    /// The first chunk after the graphics
+    pub start_externs: usize,
+    pub num_tile8: usize,
+    pub num_tile8m: usize,
+}
+
+impl Layout {
+    pub const KEEN4: Layout = Layout {
+        head_size: 3,
+        pic_table: 0,
+        picm_table: 1,
+        sprite_table: 2,
+        start_pics: 6,
+        start_picm: 121,
+        start_sprites: 124,
+        start_tile8: 521,
+        start_tile8m: 522,
+        start_tile16: 523,
+        start_tile16m: 1819,
+        start_externs: 4735,
+        num_tile8: 104,
+        num_tile8m: 20,
+    };
+
+    pub fn preset(name: &str) -> Result<Layout, String> {
+        match name {
+            "keen4" => Ok(Self::KEEN4),
+            _ => Err(format!("unknown EGAGRAPH layout: {}", name)),
+        }
+    }
+
+    /// A table of every field, as modding wikis list them for each game. Keen 4's is
+    ///
+    /// ```toml
+    /// head_size = 3
+    /// pic_table = 0
+    /// picm_table = 1
+    /// sprite_table = 2
+    /// start_pics = 6
+    /// start_picm = 121
+    /// start_sprites = 124
+    /// start_tile8 = 521
+    /// start_tile8m = 522
+    /// start_tile16 = 523
+    /// start_tile16m = 1819
+    /// start_externs = 4735
+    /// num_tile8 = 104
+    /// num_tile8m = 20
+    /// ```
+    #[cfg(feature = "formats")]
+    pub fn parse(toml: &str) -> Result<Layout, String> {
+        let layout: Layout = toml::from_str(toml).map_err(|e| e.to_string())?;
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    #[cfg(feature = "formats")]
+    pub fn load(path: &std::path::Path) -> Result<Layout, String> {
+        Self::parse(
+            &std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+        )
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=4).contains(&self.head_size) {
+            return Err("EGAHEAD offsets are 1 to 4 bytes".to_owned());
+        }
+        let starts = [
+            self.start_pics,
+            self.start_picm,
+            self.start_sprites,
+            self.start_tile8,
+            self.start_tile8m,
+            self.start_tile16,
+            self.start_tile16m,
+            self.start_externs,
+        ];
+        if starts.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err("chunk kinds must start in order, pictures to externs".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// EGADICT: 255 nodes of two little endian u16s, for a 0 or 1 bit. Values under 256 are
+/// output bytes, others point at node `value - 256`. Decoding starts from node 254
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dictionary(Vec<[u16; 2]>);
+
+impl Dictionary {
+    pub fn read(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < NODES * 4 {
+            return Err("EGADICT is too short".to_owned());
+        }
+        Ok(Self(
+            bytes
+                .chunks_exact(4)
+                .take(NODES)
+                .map(|n| {
+                    [
+                        u16::from_le_bytes([n[0], n[1]]),
+                        u16::from_le_bytes([n[2], n[3]]),
+                    ]
+                })
+                .collect(),
+        ))
+    }
+
+    /// Walk the tree reading each byte's bits from the lowest up, until `len` bytes are out
+    pub fn decompress(&self, data: &[u8], len: usize) -> Result<Vec<u8>, String> {
+        //every byte out takes at least a bit in, whatever length the file claims
+        let mut out = Vec::with_capacity(len.min(data.len() * 8));
+        let mut node = ROOT;
+        for byte in data {
+            for bit in 0..8 {
+                if out.len() == len {
+                    return Ok(out);
+                }
+                let value = self.0[node][(byte >> bit & 1) as usize] as usize;
+                if value < 256 {
+                    out.push(value as u8);
+                    node = ROOT;
+                } else {
+                    node = value - 256;
+                    if node >= NODES {
+                        return Err(format!("bad EGADICT node {}", node));
+                    }
+                }
+            }
+        }
+        if out.len() < len {
+            return Err("chunk ended early".to_owned());
+        }
+        Ok(out)
+    }
+}
+
+pub struct Archive {
+    graph: Vec<u8>,
+    offsets: Vec<Option<usize>>,
+    dictionary: Dictionary,
+    layout: Layout,
+}
+
+impl Archive {
+    pub fn open(graph: &[u8], head: &[u8], dict: &[u8], layout: Layout) -> Result<Self, String> {
+        layout.validate()?;
+        let offsets = head
+            .chunks_exact(layout.head_size)
+            .map(|entry| {
+                let offset = entry.iter().rev().fold(0, |acc, &b| acc << 8 | b as usize);
+                //all bits set marks an empty chunk
+                (offset != (1 << (8 * layout.head_size)) - 1).then_some(offset)
+            })
+            .collect();
+        Ok(Self {
+            graph: graph.to_vec(),
+            offsets,
+            dictionary: Dictionary::read(dict)?,
+            layout,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Tile chunks have a fixed size, the rest start with their decompressed length
+    fn explicit_size(&self, chunk: usize) -> Option<usize> {
+        let l = &self.layout;
+        if chunk < l.start_tile8 || chunk >= l.start_externs {
+            None
+        } else if chunk < l.start_tile8m {
+            Some(l.num_tile8 * 32)
+        } else if chunk < l.start_tile16 {
+            Some(l.num_tile8m * 40)
+        } else if chunk < l.start_tile16m {
+            Some(128)
+        } else {
+            Some(160)
+        }
+    }
+
+    /// The decompressed chunk, or None if it is empty
+    pub fn chunk(&self, chunk: usize) -> Result<Option<Vec<u8>>, String> {
+        let Some(start) = self.offsets.get(chunk).copied().flatten() else {
+            return Ok(None);
+        };
+        let end = self.offsets[chunk + 1..]
+            .iter()
+            .flatten()
+            .next()
+            .copied()
+            .unwrap_or(self.graph.len())
+            .min(self.graph.len());
+        let data = self
+            .graph
+            .get(start..end)
+            .ok_or(format!("chunk {} is outside EGAGRAPH", chunk))?;
+        let (len, data) = match self.explicit_size(chunk) {
+            Some(len) => (len, data),
+            None if data.len() >= 4 => (
+                u32::from_le_bytes(data[..4].try_into().unwrap()) as usize,
+                &data[4..],
+            ),
+            None => return Err(format!("chunk {} is too short", chunk)),
+        };
+        self.dictionary
+            .decompress(data, len)
+            .map(Some)
+            .map_err(|e| format!("chunk {}: {}", chunk, e))
+    }
+
+    /// (width in bytes, height) of each entry in a picture or sprite table
+    fn table(&self, chunk: usize, entry_size: usize) -> Result<Vec<(usize, usize)>, String> {
+        Ok(self
+            .chunk(chunk)?
+            .unwrap_or_default()
+            .chunks_exact(entry_size)
+            .map(|e| {
+                (
+                    u16::from_le_bytes([e[0], e[1]]) as usize,
+                    u16::from_le_bytes([e[2], e[3]]) as usize,
+                )
+            })
+            .collect())
+    }
+
+    /// Every picture and sprite on its own, and each kind of tile as one sheet, with names
+    /// like `pic_0006`. Masks are dropped
+    pub fn images(&self) -> Result<Vec<(String, Image)>, String> {
+        let l = &self.layout;
+        let mut images = vec![];
+        let groups = [
+            ("pic", l.start_pics, self.table(l.pic_table, 4)?, false),
+            ("picm", l.start_picm, self.table(l.picm_table, 4)?, true),
+            (
+                "sprite",
+                l.start_sprites,
+                self.table(l.sprite_table, 18)?,
+                true,
+            ),
+        ];
+        for (kind, start, sizes, masked) in groups {
+            for (i, &(width, height)) in sizes.iter().enumerate() {
+                if let Some(data) = self.chunk(start + i)? {
+                    let grid = planar(&data, width * 8, height, masked);
+                    images.push((format!("{}_{:04}", kind, start + i), ega(grid)));
+                }
+            }
+        }
+
+        let tile8 = |chunk: usize, count: usize, masked: bool| -> Result<Vec<RawGrid>, String> {
+            let tile_len = if masked { 40 } else { 32 };
+            Ok(self
+                .chunk(chunk)?
+                .unwrap_or_default()
+                .chunks_exact(tile_len)
+                .take(count)
+                .map(|tile| planar(tile, 8, 8, masked))
+                .collect())
+        };
+        let tile16 = |range: std::ops::Range<usize>, masked: bool| {
+            range
+                .map(|chunk| {
+                    Ok(self
+                        .chunk(chunk)?
+                        .map_or(Grid::new(16, 16), |tile| planar(&tile, 16, 16, masked)))
+                })
+                .collect::<Result<Vec<RawGrid>, String>>()
+        };
+        let sheets = [
+            ("tile8", 8, tile8(l.start_tile8, l.num_tile8, false)?),
+            ("tile8m", 8, tile8(l.start_tile8m, l.num_tile8m, true)?),
+            (
+                "tile16",
+                16,
+                tile16(l.start_tile16..l.start_tile16m, false)?,
+            ),
+            (
+                "tile16m",
+                16,
+                tile16(l.start_tile16m..l.start_externs, true)?,
+            ),
+        ];
+        for (kind, size, tiles) in sheets {
+            if tiles.is_empty() {
+                continue;
+            }
+            let mut strip = Grid::new(size, size * tiles.len());
+            for (i, tile) in tiles.iter().enumerate() {
+                strip.blit(&tile.as_view(), 0, i * size);
+            }
+            images.push((kind.to_owned(), ega(strip).tile(size)));
+        }
+        Ok(images)
+    }
+}
+
+fn ega(data: RawGrid) -> Image {
+    Image {
+        palette: Some(palette::EGA0.to_vec()),
+        ..Image::new(data)
+    }
+}
+
+/// id stores whole planes one after another, blue (the low bit) first and after any mask.
+/// The planar parser expects the high bit's plane first
+fn planar(data: &[u8], width: usize, height: usize, masked: bool) -> RawGrid {
+    let plane_len = width / 8 * height;
+    let skip = if masked { plane_len } else { 0 };
+    let mut planes: Vec<u8> = Vec::with_capacity(plane_len * PLANES);
+    for plane in (0..PLANES).rev() {
+        let start = skip + plane * plane_len;
+        let mut bytes = data
+            .get(start..start + plane_len)
+            .unwrap_or_default()
+            .to_vec();
+        bytes.resize(plane_len, 0);
+        planes.extend(bytes);
+    }
+    EGAPlanar.process_input(&planes, width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a complete tree that reads each byte's bits lowest first, so compresses nothing
+    fn identity_dictionary() -> Vec<u8> {
+        let index = |depth: usize, prefix: usize| ROOT - ((1 << depth) - 1 + prefix);
+        let mut nodes = vec![[0u16; 2]; NODES];
+        for depth in 0..8 {
+            for prefix in 0..1 << depth {
+                nodes[index(depth, prefix)] = [0, 1].map(|bit| {
+                    let next = prefix | bit << depth;
+                    if depth == 7 {
+                        next as u16
+                    } else {
+                        (index(depth + 1, next) + 256) as u16
+                    }
+                });
+            }
+        }
+        nodes
+            .iter()
+            .flat_map(|n| [n[0].to_le_bytes(), n[1].to_le_bytes()].concat())
+            .collect()
+    }
+
+    #[test]
+    fn huffman() {
+        //254: 0 -> 'A', 1 -> node 253. 253: 0 -> 'B', 1 -> 'C'
+        let mut dict = vec![0u8; NODES * 4];
+        dict[254 * 4..].copy_from_slice(&[b'A', 0, 253, 1]);
+        dict[253 * 4..254 * 4].copy_from_slice(&[b'B', 0, b'C', 0]);
+        let dictionary = Dictionary::read(&dict).unwrap();
+        //A=0 B=10 C=11, read from the low bit up
+        assert_eq!(dictionary.decompress(&[0b011010], 4).unwrap(), b"ABCA");
+        assert!(dictionary.decompress(&[0b011010], 9).is_err());
+        assert!(dictionary
+            .decompress(&[0b011010], u32::MAX as usize)
+            .is_err());
+
+        let identity = Dictionary::read(&identity_dictionary()).unwrap();
+        assert_eq!(
+            identity.decompress(&[0, 7, 255], 3).unwrap(),
+            vec![0, 7, 255]
+        );
+    }
+
+    #[cfg(feature = "formats")]
+    #[test]
+    fn layouts() {
+        let toml = toml::to_string(&Layout::KEEN4).unwrap();
+        assert!(toml.starts_with("head_size = 3\npic_table = 0\n"));
+        assert_eq!(Layout::parse(&toml), Ok(Layout::KEEN4));
+        assert!(Layout::parse(&toml.replace("head_size = 3", "head_size = 0")).is_err());
+        assert!(Layout::parse(&toml.replace("start_picm = 121", "start_picm = 1")).is_err());
+        assert!(Layout::parse("head_size = 3").is_err());
+    }
+
+    #[test]
+    fn archive() {
+        let layout = Layout {
+            head_size: 3,
+            pic_table: 0,
+            picm_table: 1,
+            sprite_table: 2,
+            start_pics: 3,
+            start_picm: 4,
+            start_sprites: 4,
+            start_tile8: 4,
+            start_tile8m: 5,
+            start_tile16: 5,
+            start_tile16m: 7,
+            start_externs: 7,
+            num_tile8: 1,
+            num_tile8m: 0,
+        };
+        let sized = |data: &[u8]| [&(data.len() as u32).to_le_bytes()[..], data].concat();
+        //an 8x2 picture: blue plane, green, red, intensity
+        let pic = [0xFF, 0x00, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x01];
+        let tile8 = [0xAAu8; 32];
+        let tile16 = [0x00u8; 128];
+        let chunks = [
+            Some(sized(&[1, 0, 2, 0])),
+            Some(sized(&[])),
+            Some(sized(&[])),
+            Some(sized(&pic)),
+            Some(tile8.to_vec()),
+            Some(tile16.to_vec()),
+            None,
+        ];
+        let (mut graph, mut head) = (vec![], vec![]);
+        for chunk in &chunks {
+            let offset = match chunk {
+                Some(data) => {
+                    let offset = graph.len();
+                    graph.extend(data);
+                    offset
+                }
+                None => 0xFFFFFF,
+            };
+            head.extend(&(offset as u32).to_le_bytes()[..3]);
+        }
+
+        let archive = Archive::open(&graph, &head, &identity_dictionary(), layout).unwrap();
+        assert_eq!(archive.len(), 7);
+        assert_eq!(archive.chunk(6), Ok(None));
+        let images = archive.images().unwrap();
+        let names: Vec<&str> = images.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["pic_0003", "tile8", "tile16"]);
+        assert_eq!(
+            images[0].1.data().to_vecs(),
+            vec![vec![1, 1, 1, 1, 3, 3, 3, 3], vec![0, 0, 0, 0, 0, 0, 0, 8]]
+        );
+        assert_eq!(images[1].1.data().row(0), &[15, 0, 15, 0, 15, 0, 15, 0]);
+        //the empty tile16 is kept as a blank one
+        assert_eq!(images[2].1.data().width(), 32);
+    }
+}
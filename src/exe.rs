@@ -0,0 +1,336 @@
+//! Packed DOS executables. Graphics inside one are only visible once it is unpacked to the
+//! program's load image, as DOS would have it in memory. LZEXE 0.90 and 0.91 are unpacked
+//! (following unlzexe). PKLITE is only recognized, so a scan can say why it found nothing;
+//! unpacking it is on the roadmap, pending real packed files to test against
+
+use std::fmt;
+
+pub const MAGIC: [u8; 2] = *b"MZ";
+const HEADER_LEN: usize = 0x1C;
+//where LZEXE's loader keeps its relocation table
+const LZ90_RELOCATIONS: usize = 0x19D;
+const LZ91_RELOCATIONS: usize = 0x158;
+
+/// The parts of the MZ header unpacking needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub header_paragraphs: u16,
+    pub ss: u16,
+    pub sp: u16,
+    pub ip: u16,
+    pub cs: u16,
+}
+
+impl Header {
+    pub fn read(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[..2] != MAGIC {
+            return None;
+        }
+        let word = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        Some(Self {
+            header_paragraphs: word(0x08),
+            ss: word(0x0E),
+            sp: word(0x10),
+            ip: word(0x14),
+            cs: word(0x16),
+        })
+    }
+
+    /// Where the load image starts in the file
+    pub fn load_start(&self) -> usize {
+        self.header_paragraphs as usize * 16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packer {
+    Lzexe90,
+    Lzexe91,
+    Pklite { major: u8, minor: u8 },
+}
+
+impl fmt::Display for Packer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Lzexe90 => write!(f, "LZEXE 0.90"),
+            Self::Lzexe91 => write!(f, "LZEXE 0.91"),
+            Self::Pklite { major, minor } => write!(f, "PKLITE {}.{:02}", major, minor),
+        }
+    }
+}
+
+/// Packers leave their signature just after the fixed header
+pub fn detect(bytes: &[u8]) -> Option<Packer> {
+    Header::read(bytes)?;
+    match bytes.get(HEADER_LEN..HEADER_LEN + 4)? {
+        b"LZ09" => Some(Packer::Lzexe90),
+        b"LZ91" => Some(Packer::Lzexe91),
+        [minor, major, b'P', b'K'] => Some(Packer::Pklite {
+            major: major & 0x0F,
+            minor: *minor,
+        }),
+        _ => None,
+    }
+}
+
+/// The program as loaded, with the registers and relocations (segment, offset) it starts with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unpacked {
+    pub image: Vec<u8>,
+    pub relocations: Vec<(u16, u16)>,
+    pub ss: u16,
+    pub sp: u16,
+    pub ip: u16,
+    pub cs: u16,
+}
+
+pub fn is_packed(bytes: &[u8]) -> bool {
+    detect(bytes).is_some()
+}
+
+pub fn unpack(bytes: &[u8]) -> Result<Unpacked, String> {
+    match detect(bytes) {
+        Some(Packer::Lzexe90) => lzexe(bytes, LZ90_RELOCATIONS),
+        Some(Packer::Lzexe91) => lzexe(bytes, LZ91_RELOCATIONS),
+        Some(packer) => Err(format!(
+            "{} executables can't be unpacked, only LZEXE ones",
+            packer
+        )),
+        None => Err("not a packed executable".to_owned()),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or("packed executable ends early")?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn word(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes([self.byte()?, self.byte()?]))
+    }
+}
+
+/// 16 bit little endian words of flags, used from the low bit up and refilled as soon as the
+/// last one is taken, between the bytes they describe
+struct Bits<'a> {
+    reader: Reader<'a>,
+    buffer: u16,
+    count: u8,
+}
+
+impl Bits<'_> {
+    fn bit(&mut self) -> Result<bool, String> {
+        let bit = self.buffer & 1 == 1;
+        self.count -= 1;
+        if self.count == 0 {
+            self.buffer = self.reader.word()?;
+            self.count = 16;
+        } else {
+            self.buffer >>= 1;
+        }
+        Ok(bit)
+    }
+}
+
+fn lzexe(bytes: &[u8], relocation_table: usize) -> Result<Unpacked, String> {
+    let header = Header::read(bytes).ok_or("not an executable")?;
+    let loader = header.load_start() + header.cs as usize * 16;
+    let mut info = Reader {
+        bytes,
+        position: loader,
+    };
+    //the original entry point, then the size of the packed data in paragraphs
+    let (ip, cs, sp, ss) = (info.word()?, info.word()?, info.word()?, info.word()?);
+    let packed_paragraphs = info.word()? as usize;
+
+    let relocations = lzexe_relocations(
+        Reader {
+            bytes,
+            position: loader + relocation_table,
+        },
+        relocation_table == LZ90_RELOCATIONS,
+    )?;
+
+    let start = (header.cs as usize)
+        .checked_sub(packed_paragraphs)
+        .ok_or("bad LZEXE packed size")?
+        * 16
+        + header.load_start();
+    let mut reader = Reader {
+        bytes,
+        position: start,
+    };
+    let buffer = reader.word()?;
+    let mut bits = Bits {
+        reader,
+        buffer,
+        count: 16,
+    };
+    let mut image: Vec<u8> = vec![];
+    loop {
+        if bits.bit()? {
+            image.push(bits.reader.byte()?);
+            continue;
+        }
+        let (len, span) = if !bits.bit()? {
+            let len = (bits.bit()? as usize) << 1 | bits.bit()? as usize;
+            (len + 2, 0xFF00 | bits.reader.byte()? as u16)
+        } else {
+            let low = bits.reader.byte()? as u16;
+            let high = bits.reader.byte()?;
+            let span = low | (high as u16 & !0x07) << 5 | 0xE000;
+            let len = match (high & 0x07) as usize + 2 {
+                2 => match bits.reader.byte()? {
+                    0 => break,
+                    //a segment change, only meaningful to the real loader
+                    1 => continue,
+                    len => len as usize + 1,
+                },
+                len => len,
+            };
+            (len, span)
+        };
+        let from = image
+            .len()
+            .checked_add_signed(span as i16 as isize)
+            .ok_or("LZEXE match starts before the data")?;
+        for i in from..from + len {
+            image.push(image[i]);
+        }
+    }
+    Ok(Unpacked {
+        image,
+        relocations,
+        ss,
+        sp,
+        ip,
+        cs,
+    })
+}
+
+/// 0.90 lists offsets for each of 16 segments in turn. 0.91 packs the gaps between
+/// relocations into bytes, with escapes for larger gaps, skipping a segment and ending
+fn lzexe_relocations(mut reader: Reader, v90: bool) -> Result<Vec<(u16, u16)>, String> {
+    let mut relocations = vec![];
+    if v90 {
+        for segment in 0..16u16 {
+            for _ in 0..reader.word()? {
+                relocations.push((segment * 0x1000, reader.word()?));
+            }
+        }
+        return Ok(relocations);
+    }
+    let (mut segment, mut offset) = (0u16, 0u16);
+    loop {
+        let span = match reader.byte()? {
+            0 => match reader.word()? {
+                0 => {
+                    segment = segment.wrapping_add(0x0FFF);
+                    continue;
+                }
+                1 => break,
+                span => span,
+            },
+            span => span as u16,
+        };
+        offset = offset.wrapping_add(span);
+        segment = segment.wrapping_add((offset & !0x0F) >> 4);
+        offset &= 0x0F;
+        relocations.push((segment, offset));
+    }
+    Ok(relocations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //flag words go wherever the decoder will be when it runs out of the previous one
+    struct Writer {
+        out: Vec<u8>,
+        word: usize,
+        count: usize,
+    }
+
+    impl Writer {
+        fn bit(&mut self, bit: bool) {
+            self.out[self.word + self.count / 8] |= (bit as u8) << (self.count % 8);
+            self.count += 1;
+            if self.count == 16 {
+                self.word = self.out.len();
+                self.out.extend([0, 0]);
+                self.count = 0;
+            }
+        }
+    }
+
+    fn lzexe91(packed: &[u8], relocations: &[u8]) -> Vec<u8> {
+        //a 2 paragraph header, the packed data in 2 paragraphs, then the loader
+        let mut exe = vec![0u8; 64];
+        exe[..2].copy_from_slice(&MAGIC);
+        exe[0x08] = 2;
+        exe[0x16] = 2;
+        exe[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(b"LZ91");
+        exe[32..32 + packed.len()].copy_from_slice(packed);
+        let mut loader = vec![0u8; LZ91_RELOCATIONS];
+        //ip, cs, sp, ss and the packed paragraphs
+        for (i, word) in [0x10u16, 0, 0x80, 1, 2].iter().enumerate() {
+            loader[i * 2..i * 2 + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        [exe, loader, relocations.to_vec()].concat()
+    }
+
+    #[test]
+    fn unpacks_lzexe() {
+        let mut writer = Writer {
+            out: vec![0, 0],
+            word: 0,
+            count: 0,
+        };
+        for byte in b"AB" {
+            writer.bit(true);
+            writer.out.push(*byte);
+        }
+        //a short match: 4 bytes from 2 back
+        for bit in [false, false, true, false] {
+            writer.bit(bit);
+        }
+        writer.out.push(0xFE);
+        //a long match of 11 bytes from 6 back, with its length in a third byte
+        writer.bit(false);
+        writer.bit(true);
+        writer.out.extend([0xFA, 0xF8, 10]);
+        //end
+        writer.bit(false);
+        writer.bit(true);
+        writer.out.extend([0, 0, 0]);
+
+        let exe = lzexe91(&writer.out, &[5, 0x20, 0, 1, 0]);
+        assert_eq!(detect(&exe), Some(Packer::Lzexe91));
+        let unpacked = unpack(&exe).unwrap();
+        assert_eq!(unpacked.image, b"ABABABABABABABABA");
+        assert_eq!((unpacked.cs, unpacked.ip, unpacked.ss), (0, 0x10, 1));
+        assert_eq!(unpacked.relocations, vec![(0, 5), (2, 5)]);
+    }
+
+    #[test]
+    fn pklite() {
+        let mut exe = vec![0u8; 64];
+        exe[..2].copy_from_slice(&MAGIC);
+        exe[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&[0x03, 0x11, b'P', b'K']);
+        assert_eq!(detect(&exe), Some(Packer::Pklite { major: 1, minor: 3 }));
+        assert!(unpack(&exe).unwrap_err().contains("PKLITE 1.03"));
+        assert_eq!(detect(b"MZ"), None);
+        assert!(!is_packed(&[0; 64]));
+    }
+}
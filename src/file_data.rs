@@ -1,3 +1,6 @@
+use crate::compression::Compression;
+use crate::detect::scan::{self, Region};
+use crate::exe;
 use crate::image::Image;
 use crate::parser::ParserType;
 use crate::ImageType;
@@ -8,6 +11,11 @@ impl Raw {
     pub fn new(data: &[u8]) -> Self {
         Self(data.to_owned())
     }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+
     fn byte_count(&self) -> usize {
         self.0.len()
     }
@@ -28,8 +36,67 @@ impl Raw {
         itype.heights(self.byte_count(), width)
     }
 
-    pub fn parse(&self, parser: ParserType, width: usize) -> Image {
-        Image(parser.process_input(&self.0, width))
+    /// `length` bytes starting at `offset`, or everything after it. Clamped to the end of the data
+    fn range(&self, offset: usize, length: Option<usize>) -> &[u8] {
+        let start = offset.min(self.byte_count());
+        let end = length.map_or(self.byte_count(), |l| (start + l).min(self.byte_count()));
+        &self.0[start..end]
+    }
+
+    pub fn slice(&self, offset: usize, length: Option<usize>) -> Raw {
+        Raw::new(self.range(offset, length))
+    }
+
+    pub fn parse(&self, parser: ParserType, width: usize) -> Result<Image, String> {
+        self.parse_range(parser, width, 0, None)
+    }
+
+    /// Parse only part of the data, e.g. to skip a header
+    pub fn parse_range(
+        &self,
+        parser: ParserType,
+        width: usize,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<Image, String> {
+        Ok(Image {
+            offset,
+            length,
+            ..parser.decode(self.range(offset, length), width)?
+        })
+    }
+
+    /// Bits per byte, 0.0 for constant data up to 8.0 for random or compressed data
+    pub fn entropy(&self) -> f64 {
+        scan::entropy(&self.0)
+    }
+
+    /// Likely graphics regions, e.g. inside an executable or archive. See [scan::scan]
+    pub fn scan(&self, window: usize, step: usize) -> Vec<Region> {
+        scan::scan(self, window, step)
+    }
+
+    /// The load image of a packed executable, where its graphics can be found. See [exe::unpack]
+    pub fn unpack(&self) -> Result<Raw, String> {
+        Ok(Raw::new(&exe::unpack(&self.0)?.image))
+    }
+
+    /// Parse part of the data after decompressing it. The offset and length are of the compressed data
+    pub fn parse_compressed(
+        &self,
+        parser: ParserType,
+        width: usize,
+        offset: usize,
+        length: Option<usize>,
+        compression: Compression,
+    ) -> Result<Image, String> {
+        let raw = Raw::new(&compression.decompress(self.range(offset, length))?);
+        Ok(Image {
+            offset,
+            length,
+            compression: Some(compression),
+            ..raw.parse(parser, width)?
+        })
     }
 
     pub fn previews(&self) -> Vec<Image> {
@@ -37,7 +104,7 @@ impl Raw {
         // }else {
         self.widths(ImageType::CGA)
             .iter()
-            .map(|w| Image(ParserType::CGA.process_input(&self.0, *w as usize)))
+            .filter_map(|w| self.parse(ParserType::CGA, *w as usize).ok())
             .collect()
         // }
     }
@@ -0,0 +1,376 @@
+use std::ops::{Index, IndexMut};
+
+/// A flat, row-major 2d buffer. `stride` is the distance between the starts of two rows,
+/// which is at least `width` (it can be larger when wrapping a buffer with row padding).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Grid<T> {
+    data: Vec<T>,
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+
+/// A borrowed rectangular window into a [Grid]
+#[derive(Debug)]
+pub struct GridView<'a, T> {
+    grid: &'a Grid<T>,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl<T: Clone + Default> Grid<T> {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self::filled(width, height, T::default())
+    }
+
+    /// Chunk a flat buffer into rows of `width`, padding an incomplete last row with `T::default()`
+    pub fn from_flat(mut data: Vec<T>, width: usize) -> Self {
+        if width == 0 {
+            return Self::default();
+        }
+        let height = data.len().div_ceil(width);
+        data.resize(width * height, T::default());
+        Self {
+            data,
+            width,
+            height,
+            stride: width,
+        }
+    }
+}
+
+impl<T: Clone> Grid<T> {
+    pub fn filled(width: usize, height: usize, value: T) -> Self {
+        Self {
+            data: vec![value; width * height],
+            width,
+            height,
+            stride: width,
+        }
+    }
+
+    /// Wrap an existing buffer whose rows are `stride` apart
+    pub fn from_raw_parts(data: Vec<T>, width: usize, height: usize, stride: usize) -> Self {
+        assert!(stride >= width, "stride must be at least the width");
+        assert!(
+            height == 0 || data.len() >= stride * (height - 1) + width,
+            "buffer too small for the given dimensions"
+        );
+        Self {
+            data,
+            width,
+            height,
+            stride,
+        }
+    }
+
+    pub fn to_vecs(&self) -> Vec<Vec<T>> {
+        self.rows().map(|row| row.to_vec()).collect()
+    }
+
+    /// Copy the pixels of a view in, with its top left corner at (x, y). Anything falling outside is clipped
+    pub fn blit(&mut self, source: &GridView<T>, x: usize, y: usize) {
+        for (j, row) in source.rows().enumerate() {
+            if y + j >= self.height || x >= self.width {
+                break;
+            }
+            let len = row.len().min(self.width - x);
+            self.row_mut(y + j)[x..x + len].clone_from_slice(&row[..len]);
+        }
+    }
+
+    /// Copy into a new grid, dropping any stride padding
+    pub fn compact(&self) -> Self {
+        if self.stride == self.width {
+            return self.clone();
+        }
+        Self {
+            data: self.pixels().cloned().collect(),
+            width: self.width,
+            height: self.height,
+            stride: self.width,
+        }
+    }
+}
+
+impl<T> Grid<T> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn len(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<&T> {
+        if x < self.width && y < self.height {
+            Some(&self.data[y * self.stride + x])
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize) -> Option<&mut T> {
+        if x < self.width && y < self.height {
+            Some(&mut self.data[y * self.stride + x])
+        } else {
+            None
+        }
+    }
+
+    pub fn row(&self, y: usize) -> &[T] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [T] {
+        let start = y * self.stride;
+        &mut self.data[start..start + self.width]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[T]> {
+        (0..self.height).map(move |y| self.row(y))
+    }
+
+    /// Every pixel, row by row, skipping stride padding
+    pub fn pixels(&self) -> impl Iterator<Item = &T> {
+        self.rows().flatten()
+    }
+
+    pub fn view(&self, x: usize, y: usize, width: usize, height: usize) -> GridView<'_, T> {
+        assert!(
+            x + width <= self.width && y + height <= self.height,
+            "view out of bounds"
+        );
+        GridView {
+            grid: self,
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn as_view(&self) -> GridView<'_, T> {
+        self.view(0, 0, self.width, self.height)
+    }
+
+    /// Views of `height` consecutive rows. The last one may be shorter
+    pub fn row_chunks(&self, height: usize) -> impl Iterator<Item = GridView<'_, T>> {
+        (0..self.height)
+            .step_by(height.max(1))
+            .map(move |y| self.view(0, y, self.width, height.min(self.height - y)))
+    }
+
+    pub fn map<U, F: FnMut(&T) -> U>(&self, f: F) -> Grid<U> {
+        Grid {
+            data: self.pixels().map(f).collect(),
+            width: self.width,
+            height: self.height,
+            stride: self.width,
+        }
+    }
+
+    pub fn into_flat(self) -> Vec<T> {
+        if self.stride == self.width {
+            let mut data = self.data;
+            data.truncate(self.width * self.height);
+            data
+        } else {
+            let (width, stride) = (self.width, self.stride);
+            self.data
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| i % stride < width)
+                .map(|(_, p)| p)
+                .take(width * self.height)
+                .collect()
+        }
+    }
+}
+
+impl<T> Clone for GridView<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for GridView<'_, T> {}
+
+impl<'a, T> GridView<'a, T> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<&'a T> {
+        if x < self.width && y < self.height {
+            self.grid.get(self.x + x, self.y + y)
+        } else {
+            None
+        }
+    }
+
+    pub fn row(&self, y: usize) -> &'a [T] {
+        assert!(y < self.height, "row out of bounds");
+        &self.grid.row(self.y + y)[self.x..self.x + self.width]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &'a [T]> + '_ {
+        (0..self.height).map(move |y| self.row(y))
+    }
+
+    pub fn pixels(&self) -> impl Iterator<Item = &'a T> + '_ {
+        self.rows().flatten()
+    }
+
+    pub fn view(&self, x: usize, y: usize, width: usize, height: usize) -> GridView<'a, T> {
+        assert!(
+            x + width <= self.width && y + height <= self.height,
+            "view out of bounds"
+        );
+        GridView {
+            grid: self.grid,
+            x: self.x + x,
+            y: self.y + y,
+            width,
+            height,
+        }
+    }
+}
+
+impl<T: Clone> GridView<'_, T> {
+    pub fn to_grid(&self) -> Grid<T> {
+        Grid {
+            data: self.pixels().cloned().collect(),
+            width: self.width,
+            height: self.height,
+            stride: self.width,
+        }
+    }
+}
+
+impl<T: PartialEq> PartialEq for GridView<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && self.rows().zip(other.rows()).all(|(a, b)| a == b)
+    }
+}
+
+impl<T> Index<(usize, usize)> for Grid<T> {
+    type Output = T;
+
+    fn index(&self, (x, y): (usize, usize)) -> &T {
+        self.get(x, y).expect("pixel out of bounds")
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Grid<T> {
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut T {
+        self.get_mut(x, y).expect("pixel out of bounds")
+    }
+}
+
+/// Rows are expected to be the same length; ragged rows are padded to the longest with `T::default()`
+impl<T: Clone + Default> From<Vec<Vec<T>>> for Grid<T> {
+    fn from(rows: Vec<Vec<T>>) -> Self {
+        let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+        let height = rows.len();
+        let mut data = Vec::with_capacity(width * height);
+        for mut row in rows {
+            row.resize(width, T::default());
+            data.extend(row);
+        }
+        Self {
+            data,
+            width,
+            height,
+            stride: width,
+        }
+    }
+}
+
+impl<T: Clone> From<Grid<T>> for Vec<Vec<T>> {
+    fn from(grid: Grid<T>) -> Self {
+        grid.to_vecs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grid::Grid;
+
+    fn counting(width: usize, height: usize) -> Grid<u8> {
+        Grid::from_flat((0..(width * height) as u8).collect(), width)
+    }
+
+    #[test]
+    fn from_flat_pads_last_row() {
+        let grid = Grid::from_flat(vec![1u8, 2, 3, 4, 5], 2);
+        assert_eq!(grid.height(), 3);
+        assert_eq!(grid.to_vecs(), vec![vec![1, 2], vec![3, 4], vec![5, 0]]);
+    }
+
+    #[test]
+    fn from_vecs_round_trip() {
+        let vecs = vec![vec![0u8, 1, 2], vec![3, 4, 5]];
+        let grid = Grid::from(vecs.clone());
+        assert_eq!((grid.width(), grid.height()), (3, 2));
+        assert_eq!(grid[(2, 1)], 5);
+        assert_eq!(Vec::<Vec<u8>>::from(grid), vecs);
+    }
+
+    #[test]
+    fn stride() {
+        let grid = Grid::from_raw_parts(vec![0u8, 1, 9, 2, 3, 9], 2, 2, 3);
+        assert_eq!(grid.to_vecs(), vec![vec![0, 1], vec![2, 3]]);
+        assert_eq!(grid.pixels().count(), 4);
+        assert_eq!(grid.compact().into_flat(), vec![0, 1, 2, 3]);
+        assert_eq!(grid.into_flat(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn views() {
+        let grid = counting(4, 4);
+        let view = grid.view(1, 1, 2, 3);
+        assert_eq!(view.row(0), &[5, 6]);
+        assert_eq!(view.get(1, 2), Some(&14));
+        assert_eq!(view.get(2, 0), None);
+        assert_eq!(
+            view.view(1, 1, 1, 2).to_grid().to_vecs(),
+            vec![vec![10], vec![14]]
+        );
+
+        let chunks: Vec<_> = grid.row_chunks(3).map(|v| v.height()).collect();
+        assert_eq!(chunks, vec![3, 1]);
+    }
+
+    #[test]
+    fn blit() {
+        let source = counting(2, 2);
+        let mut grid = Grid::<u8>::new(3, 3);
+        grid.blit(&source.as_view(), 2, 1);
+        assert_eq!(
+            grid.to_vecs(),
+            vec![vec![0, 0, 0], vec![0, 0, 0], vec![0, 0, 2]]
+        );
+    }
+}
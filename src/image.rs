@@ -1,25 +1,275 @@
+use std::fmt;
+
 use factor::factor::factor;
 
-use crate::RawGrid;
+use crate::color::palette;
+use crate::compression::Compression;
+use crate::lbm::ColorRange;
+use crate::parser::ParserType;
+use crate::tilemap::Tileset;
+use crate::{ColorPalette, Grid, ImageType, RawGrid};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "sidecar",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum TileOrder {
+    #[default]
+    RowMajor,
+    ColumnMajor,
+}
+
+impl TileOrder {
+    pub fn type_str(str: &str) -> Result<TileOrder, String> {
+        match str {
+            "row" | "row_major" => Ok(TileOrder::RowMajor),
+            "column" | "column_major" => Ok(TileOrder::ColumnMajor),
+            _ => Err(format!("unknown tile order: {}", str)),
+        }
+    }
+}
+
+impl fmt::Display for TileOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::RowMajor => write!(f, "row"),
+            Self::ColumnMajor => write!(f, "column"),
+        }
+    }
+}
+
+/// How tiles are arranged in a sheet. The default is as many to a row as fit in
+/// [Image::MAX_WIDTH], in order across each row, with nothing between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "sidecar",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct TileOptions {
+    #[cfg_attr(feature = "sidecar", serde(skip_serializing_if = "Option::is_none"))]
+    pub columns: Option<usize>,
+    pub order: TileOrder,
+    /// Pixels between tiles
+    pub gutter: usize,
+    pub gutter_color: u8,
+    /// Color of the cells an incomplete last row (or column) leaves empty
+    pub padding_color: u8,
+}
+
+impl TileOptions {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// How a strip of tiles (one tile per `tile_height` rows) was laid out side by side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileLayout {
+    pub tile_height: usize,
+    pub tiles_per_row: usize,
+    pub tile_count: usize,
+    pub options: TileOptions,
+}
+
+impl TileLayout {
+    pub fn new(width: usize, height: usize, tile_height: usize) -> Self {
+        Self::with_options(width, height, tile_height, TileOptions::default())
+    }
+
+    pub fn with_options(
+        width: usize,
+        height: usize,
+        tile_height: usize,
+        options: TileOptions,
+    ) -> Self {
+        let gutter = options.gutter;
+        let fit = (Image::MAX_WIDTH + gutter) / (width + gutter);
+        Self {
+            tile_height,
+            tiles_per_row: options.columns.unwrap_or(fit).max(1),
+            tile_count: height.div_ceil(tile_height),
+            options,
+        }
+    }
+
+    /// Tiles down and across the sheet
+    fn shape(&self) -> (usize, usize) {
+        let rows = self.tile_count.div_ceil(self.tiles_per_row);
+        let columns = match self.options.order {
+            TileOrder::RowMajor => self.tiles_per_row.min(self.tile_count),
+            TileOrder::ColumnMajor => self.tile_count.div_ceil(rows.max(1)),
+        };
+        (rows, columns)
+    }
+
+    /// Where the top left of tile `i`, `width` wide, goes in the sheet
+    pub fn position(&self, i: usize, width: usize) -> (usize, usize) {
+        let (rows, columns) = self.shape();
+        let (column, row) = match self.options.order {
+            TileOrder::RowMajor => (i % columns.max(1), i / columns.max(1)),
+            TileOrder::ColumnMajor => (i / rows.max(1), i % rows.max(1)),
+        };
+        let gutter = self.options.gutter;
+        (column * (width + gutter), row * (self.tile_height + gutter))
+    }
+
+    pub fn apply<T: Clone + From<u8>>(&self, data: &Grid<T>) -> Grid<T> {
+        let width = data.width();
+        let (rows, columns) = self.shape();
+        let gutter = self.options.gutter;
+        let sheet_width = (columns * (width + gutter)).saturating_sub(gutter);
+        let sheet_height = (rows * (self.tile_height + gutter)).saturating_sub(gutter);
+
+        let mut tiled = Grid::filled(
+            sheet_width,
+            sheet_height,
+            T::from(self.options.padding_color),
+        );
+        let gutter_color = T::from(self.options.gutter_color);
+        for y in 0..sheet_height {
+            let row = tiled.row_mut(y);
+            if y % (self.tile_height + gutter) >= self.tile_height {
+                row.fill(gutter_color.clone());
+                continue;
+            }
+            for column in 1..columns {
+                let x = column * (width + gutter) - gutter;
+                row[x..x + gutter].fill(gutter_color.clone());
+            }
+        }
+        for (i, tile) in data.row_chunks(self.tile_height).enumerate() {
+            let (x, y) = self.position(i, width);
+            tiled.blit(&tile, x, y);
+        }
+        tiled
+    }
+}
 
-pub struct Image(pub RawGrid);
+/// Decoded pixels along with how they were produced: `width` is the width they were decoded at,
+/// which stays the same after tiling rearranges `data`
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub data: RawGrid,
+    pub parser: Option<ParserType>,
+    pub width: usize,
+    pub offset: usize,
+    pub length: Option<usize>,
+    /// Applied to the offset/length range before parsing
+    pub compression: Option<Compression>,
+    pub layout: Option<TileLayout>,
+    pub palette: Option<ColorPalette>,
+    /// Color cycling from an LBM, kept to write back out
+    pub color_ranges: Vec<ColorRange>,
+}
 
 impl Image {
-    const MAX_WIDTH: usize = 320;
+    pub const MAX_WIDTH: usize = 320;
+
+    pub fn new(data: RawGrid) -> Self {
+        Self {
+            width: data.width(),
+            data,
+            parser: None,
+            offset: 0,
+            length: None,
+            compression: None,
+            layout: None,
+            palette: None,
+            color_ranges: vec![],
+        }
+    }
+
+    pub fn data(&self) -> &RawGrid {
+        &self.data
+    }
+
+    /// A known palette decides, as formats like pcx can hold either
+    pub fn image_type(&self) -> ImageType {
+        match &self.palette {
+            Some(palette) if palette.len() <= ImageType::CGA.palette_length() => ImageType::CGA,
+            Some(_) => ImageType::EGA,
+            None => self.parser.map_or(ImageType::CGA, |p| p.image_type()),
+        }
+    }
+
+    pub fn palette(&self) -> ColorPalette {
+        self.palette
+            .clone()
+            .unwrap_or_else(|| match self.image_type() {
+                ImageType::EGA => palette::EGA0.to_vec(),
+                ImageType::CGA => palette::CGA1.to_vec(),
+            })
+    }
 
-    pub fn data(&self) -> RawGrid {
-        self.0.clone()
+    pub fn tile(&self, tile_height: usize) -> Image {
+        self.tile_with(tile_height, TileOptions::default())
+    }
+
+    pub fn tile_with(&self, tile_height: usize, options: TileOptions) -> Image {
+        let layout = TileLayout::with_options(self.width, self.data.height(), tile_height, options);
+        Image {
+            data: layout.apply(&self.data),
+            parser: self.parser,
+            width: self.width,
+            offset: self.offset,
+            length: self.length,
+            compression: self.compression,
+            layout: Some(layout),
+            palette: self.palette.clone(),
+            color_ranges: self.color_ranges.clone(),
+        }
+    }
+
+    /// The image cut into tiles, whether it is still a strip as decoded or was laid out by
+    /// [Image::tile], whose padding after the last tile is left out
+    pub fn tileset(&self, tile_width: usize, tile_height: usize) -> Tileset {
+        match self.layout {
+            Some(layout) if tile_width == self.width && tile_height == layout.tile_height => {
+                let tiles = (0..layout.tile_count)
+                    .map(|i| {
+                        let (x, y) = layout.position(i, tile_width);
+                        self.data.view(x, y, tile_width, tile_height).to_grid()
+                    })
+                    .collect();
+                Tileset {
+                    tiles,
+                    tile_width,
+                    tile_height,
+                }
+            }
+            _ => Tileset::from_grid(&self.data, tile_width, tile_height),
+        }
+    }
+
+    /// The pixels as decoded, a strip `width` wide: a laid out sheet's gutters and padding are
+    /// dropped and its tiles put back in order, so encoding gives back the original bytes
+    pub fn untiled(&self) -> Image {
+        match self.layout {
+            Some(layout) => Image {
+                data: self.tileset(self.width, layout.tile_height).strip(),
+                layout: None,
+                ..self.clone()
+            },
+            None => self.clone(),
+        }
     }
 
     pub fn pixel_count(&self) -> usize {
         self.height() * self.width()
     }
 
+    /// Height as decoded, before any tiling
     pub fn height(&self) -> usize {
-        self.0.len()
+        match self.layout {
+            Some(layout) => layout.tile_count * layout.tile_height,
+            None => self.data.height(),
+        }
     }
     pub fn width(&self) -> usize {
-        self.0[0].len()
+        self.width
     }
 
     pub fn is_fullscreen(&self) -> bool {
@@ -45,51 +295,76 @@ impl Image {
         if !self.is_fullscreen() && self.width() == 320 {
             format!(
                 "Image appears to not be fullscreen 320*200.\
-                It may be tiled, try setting a narrower -w width to detect tiles.\n\
+                It may be tiled, try setting a narrower -w width (or --auto-width) to detect tiles.\n\
                 Possible widths: {:?}",
                 self.width_factors()
             )
-        } else if self.is_tall() {
+        } else if self.layout.is_none() && self.is_tall() {
             format!("Image height appears to >= 4x its width.\
-                If there are tiles, setting a smaller -t tile_height will make a more compact view\n\
+                If there are tiles, setting a smaller -t tile_height (or --auto-tile) will make a more compact view\n\
                 Possible heights: {:?}", self.height_factors())
         } else {
             "".to_string()
         }
     }
-}
-
-pub fn tile<T: std::clone::Clone>(data: Vec<Vec<T>>, tile_height: usize) -> Vec<Vec<T>> {
-    let width = data[0].len();
-    let tiles_per_row = Image::MAX_WIDTH / width;
 
-    data.chunks(tiles_per_row * tile_height)
-        .flat_map(|tile_row| concat_tiles(tile_row.to_vec(), tile_height))
-        .collect()
-}
-
-fn concat_tiles<T: std::clone::Clone>(tiles: Vec<Vec<T>>, num_rows: usize) -> Vec<Vec<T>> {
-    //TODO; make this into a fold?
-    let mut rows: Vec<Vec<T>> = vec![vec![]; num_rows];
-    for tile in tiles.chunks(num_rows) {
-        for (i, row) in tile.iter().enumerate() {
-            rows[i].extend(row.clone());
+    /// A one line summary of how the image was decoded, for embedding in outputs
+    pub fn provenance(&self) -> String {
+        let mut parts = vec![
+            format!("parser={}", self.parser.map_or("unknown", |p| p.name())),
+            format!("width={}", self.width),
+            format!("offset={}", self.offset),
+        ];
+        if let Some(length) = self.length {
+            parts.push(format!("length={}", length));
         }
+        if let Some(compression) = self.compression {
+            parts.push(format!("compression={}", compression));
+        }
+        if let Some(layout) = self.layout {
+            parts.push(format!("tile_height={}", layout.tile_height));
+            let options = layout.options;
+            if let Some(columns) = options.columns {
+                parts.push(format!("columns={}", columns));
+            }
+            if options.order != TileOrder::default() {
+                parts.push(format!("order={}", options.order));
+            }
+            if options.gutter > 0 {
+                parts.push(format!(
+                    "gutter={}:{}",
+                    options.gutter, options.gutter_color
+                ));
+            }
+            if options.padding_color > 0 {
+                parts.push(format!("padding_color={}", options.padding_color));
+            }
+        }
+        if let Some(name) = self.palette.as_ref().and_then(palette::abbr) {
+            parts.push(format!("palette={}", name));
+        }
+        parts.join(" ")
     }
-    rows
+}
+
+pub fn tile<T: Clone + From<u8>>(data: &Grid<T>, tile_height: usize) -> Grid<T> {
+    TileLayout::new(data.width(), data.height(), tile_height).apply(data)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::image::{self, Image};
+    use crate::file_data;
+    use crate::image::{self, Image, TileOptions, TileOrder};
     use crate::parser::ParserType;
+    use crate::tilemap::Tileset;
+    use crate::Grid;
 
     #[test]
     fn basic_properties() {
         let data: u32 = 0b00011011000110110001101100011011;
-        let parser = ParserType::type_str("cga");
-        let parsed = parser.process_input(&data.to_be_bytes(), 4);
-        let mut image = Image(parsed);
+        let parser = ParserType::type_str("cga").unwrap();
+        let parsed = parser.decode(&data.to_be_bytes(), 4).unwrap().data;
+        let mut image = Image::new(parsed);
 
         assert_eq!(image.pixel_count(), 16);
         assert_eq!(image.width(), 4);
@@ -99,49 +374,139 @@ mod tests {
         assert_eq!(image.height_factors(), [2]);
         assert!(!image.is_tall());
 
-        let parsed = parser.process_input(
-            &0b0001101100011011000110110001101100011011000110110001101100011011_u64.to_be_bytes(),
-            2,
-        );
-        image = Image(parsed);
+        let parsed = parser
+            .decode(
+                &0b0001101100011011000110110001101100011011000110110001101100011011_u64
+                    .to_be_bytes(),
+                2,
+            )
+            .unwrap()
+            .data;
+        image = Image::new(parsed);
         assert!(image.is_tall());
     }
 
     #[test]
-    fn concat_vecs() {
-        let tiles = vec![
+    fn tiled_metadata() {
+        let data: u64 = 0b0001101100011011000110110001101100011011000110110001101100011011;
+        let image = file_data::Raw::new(&data.to_be_bytes())
+            .parse(ParserType::CGA, 2)
+            .unwrap();
+        assert!(image.is_tall());
+        assert!(!image.suggestions().is_empty());
+
+        let tiled = image.tile(2);
+        assert_eq!(tiled.data().width(), 16);
+        assert_eq!((tiled.width(), tiled.height()), (2, 16));
+        assert_eq!(tiled.suggestions(), "");
+        assert_eq!(
+            tiled.provenance(),
+            "parser=cga width=2 offset=0 tile_height=2"
+        );
+    }
+
+    #[test]
+    fn slices_tiles() {
+        //three 160x1 tiles, two to a row once laid out
+        let strip = Grid::from_flat((0..480).map(|i| (i / 160) as u8 + 1).collect(), 160);
+        let image = Image::new(strip);
+        assert_eq!(image.tileset(160, 1).len(), 3);
+        let tiled = image.tile(1);
+        assert_eq!(Tileset::from_grid(tiled.data(), 160, 1).len(), 4);
+        let tileset = tiled.tileset(160, 1);
+        assert_eq!(tileset.len(), 3);
+        assert_eq!(tileset.tile(2), Some(&Grid::filled(160, 1, 3)));
+    }
+
+    #[test]
+    fn tile_options() {
+        //five 2x1 tiles
+        let strip = Grid::from_flat((0..10).map(|i| i / 2 + 1).collect(), 2);
+        let image = Image::new(strip);
+        let options = TileOptions {
+            columns: Some(2),
+            gutter: 1,
+            gutter_color: 9,
+            padding_color: 7,
+            ..TileOptions::default()
+        };
+        let tiled = image.tile_with(1, options);
+        assert_eq!(
+            tiled.data().to_vecs(),
+            vec![
+                vec![1, 1, 9, 2, 2],
+                vec![9; 5],
+                vec![3, 3, 9, 4, 4],
+                vec![9; 5],
+                vec![5, 5, 9, 7, 7],
+            ]
+        );
+        assert_eq!(
+            tiled.provenance(),
+            "parser=unknown width=2 offset=0 tile_height=1 columns=2 gutter=1:9 padding_color=7"
+        );
+
+        let columns = image.tile_with(
+            1,
+            TileOptions {
+                order: TileOrder::ColumnMajor,
+                ..options
+            },
+        );
+        assert_eq!(columns.data().row(0), &[1, 1, 9, 4, 4]);
+        assert_eq!(columns.data().row(2), &[2, 2, 9, 5, 5]);
+        assert_eq!(columns.data().row(4), &[3, 3, 9, 7, 7]);
+        let tileset = columns.tileset(2, 1);
+        assert_eq!(tileset.len(), 5);
+        assert_eq!(tileset.tile(4), Some(&Grid::filled(2, 1, 5)));
+        assert_eq!(columns.untiled().data(), image.data());
+        assert!(columns.untiled().layout.is_none());
+    }
+
+    #[test]
+    fn concat_tiles() {
+        let tiles = Grid::from(vec![
             vec![0, 1],
             vec![2, 3],
             vec![4, 5],
             vec![6, 7],
             vec![8, 9],
             vec![10, 11],
-        ];
-        let new_vecs = image::concat_tiles(tiles, 2);
+        ]);
+        let new_vecs = image::tile(&tiles, 2).to_vecs();
         assert_eq!(
             vec![vec![0, 1, 4, 5, 8, 9], vec![2, 3, 6, 7, 10, 11]],
             new_vecs
         );
     }
 
+    #[test]
+    fn tiling_pads_partial_tile() {
+        let tiles = Grid::from(vec![vec![1, 1], vec![2, 2], vec![3, 3]]);
+        assert_eq!(
+            image::tile(&tiles, 2).to_vecs(),
+            vec![vec![1, 1, 3, 3], vec![2, 2, 0, 0]]
+        );
+    }
+
     #[test]
     //rework these tests to actually be wider than max_width, or do something clever to overwrite it
     fn tiling() {
         let data: u32 = 0b00011011000110110001101100011011;
-        let parser = ParserType::type_str("cga");
-        let parsed = parser.process_input(&data.to_be_bytes(), 2);
+        let parser = ParserType::type_str("cga").unwrap();
+        let parsed = parser.decode(&data.to_be_bytes(), 2).unwrap().data;
 
-        let tiled = image::tile(Image(parsed).data(), 2);
+        let tiled = image::tile(Image::new(parsed).data(), 2);
         assert_eq!(
-            tiled,
+            tiled.to_vecs(),
             [vec![0, 1, 0, 1, 0, 1, 0, 1], vec![2, 3, 2, 3, 2, 3, 2, 3],]
         );
 
         let data: u64 = 0b0001101100011011000110110001101100011011000110110001101100011011;
-        let parsed = parser.process_input(&data.to_be_bytes(), 2);
-        let tiled = image::tile(Image(parsed).data(), 2);
+        let parsed = parser.decode(&data.to_be_bytes(), 2).unwrap().data;
+        let tiled = image::tile(Image::new(parsed).data(), 2);
         assert_eq!(
-            tiled,
+            tiled.to_vecs(),
             vec![
                 vec![0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1],
                 vec![2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3],
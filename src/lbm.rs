@@ -0,0 +1,421 @@
+//! Deluxe Paint's IFF images (.LBM/.IFF): ILBM with interleaved bitplanes, or chunky PBM,
+//! optionally ByteRun1 compressed, with a CMAP palette and CRNG color cycling ranges.
+//! Images of up to 16 colors are supported
+//! https://moddingwiki.shikadi.net/wiki/LBM_Format
+
+use std::path::Path;
+
+use crate::color::{palette, Color};
+use crate::{ColorPalette, Grid, RawGrid};
+
+pub const EXTENSIONS: [&str; 3] = ["lbm", "iff", "ilbm"];
+const MAX_COLORS: usize = 16;
+
+/// A CRNG color cycling range: palette entries `low..=high` rotate while animating
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorRange {
+    pub low: u8,
+    pub high: u8,
+    /// 16384 is 60 steps a second
+    pub rate: i16,
+    pub active: bool,
+    pub reverse: bool,
+}
+
+/// A decoded image with everything Deluxe Paint stored alongside it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lbm {
+    pub data: RawGrid,
+    pub palette: ColorPalette,
+    pub ranges: Vec<ColorRange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bitmap {
+    width: usize,
+    height: usize,
+    planes: usize,
+    masking: u8,
+    compression: u8,
+}
+
+const MASK_PLANE: u8 = 1;
+const BYTERUN1: u8 = 1;
+
+type Chunks<'a> = Vec<(&'a [u8], &'a [u8])>;
+
+/// The form type (ILBM or PBM) and its chunks
+fn chunks(bytes: &[u8]) -> Result<(&[u8], Chunks<'_>), String> {
+    if bytes.len() < 12 || &bytes[0..4] != b"FORM" {
+        return Err("not an IFF file".to_owned());
+    }
+    let form = &bytes[8..12];
+    if form != b"ILBM" && form != b"PBM " {
+        return Err(format!(
+            "unsupported IFF type {}",
+            String::from_utf8_lossy(form)
+        ));
+    }
+    let end = (8 + u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize).min(bytes.len());
+    let mut chunks = vec![];
+    let mut at = 12;
+    while at + 8 <= end {
+        let id = &bytes[at..at + 4];
+        let len = u32::from_be_bytes(bytes[at + 4..at + 8].try_into().unwrap()) as usize;
+        let data = &bytes[at + 8..(at + 8 + len).min(end)];
+        chunks.push((id, data));
+        //chunks are padded to an even length
+        at += 8 + len + len % 2;
+    }
+    Ok((form, chunks))
+}
+
+fn find<'a>(chunks: &Chunks<'a>, id: &[u8]) -> Option<&'a [u8]> {
+    chunks.iter().find(|(i, _)| *i == id).map(|(_, data)| *data)
+}
+
+fn bitmap(chunks: &Chunks) -> Result<Bitmap, String> {
+    let bmhd = find(chunks, b"BMHD")
+        .filter(|b| b.len() >= 20)
+        .ok_or("missing BMHD chunk")?;
+    let word = |i: usize| u16::from_be_bytes([bmhd[i], bmhd[i + 1]]) as usize;
+    Ok(Bitmap {
+        width: word(0),
+        height: word(2),
+        planes: bmhd[8] as usize,
+        masking: bmhd[9],
+        compression: bmhd[10],
+    })
+}
+
+/// The CMAP mapped to the nearest of the 16 colors, padded or cut to a CGA or EGA palette
+fn read_palette(chunks: &Chunks, colors: usize) -> ColorPalette {
+    let len = if colors <= 4 { 4 } else { MAX_COLORS };
+    let default = if len == 4 {
+        palette::CGA1.to_vec()
+    } else {
+        palette::EGA0.to_vec()
+    };
+    let cmap: Vec<Color> = find(chunks, b"CMAP")
+        .unwrap_or_default()
+        .chunks_exact(3)
+        .map(|rgb| Color::nearest(u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]])))
+        .collect();
+    (0..len)
+        .map(|i| cmap.get(i).unwrap_or(&default[i]).clone())
+        .collect()
+}
+
+/// Whether [decode] can read the file: an ILBM of 1 to 4 planes, or a PBM whose palette has no
+/// more than 16 colors (a 256 color one may still only use 16), uncompressed or ByteRun1
+pub fn is_supported(bytes: &[u8]) -> bool {
+    let Ok((form, chunks)) = chunks(bytes) else {
+        return false;
+    };
+    let Ok(bitmap) = bitmap(&chunks) else {
+        return false;
+    };
+    let fits = if form == b"PBM " {
+        find(&chunks, b"CMAP").map_or(0, |cmap| cmap.len() / 3) <= MAX_COLORS
+    } else {
+        (1..=4).contains(&bitmap.planes)
+    };
+    fits && matches!(bitmap.compression, 0 | BYTERUN1) && find(&chunks, b"BODY").is_some()
+}
+
+/// Just the palette, without decoding the image
+pub fn palette(bytes: &[u8]) -> Result<ColorPalette, String> {
+    let (form, chunks) = chunks(bytes)?;
+    let colors = if form == b"PBM " {
+        MAX_COLORS
+    } else {
+        1 << bitmap(&chunks)?.planes.min(4)
+    };
+    Ok(read_palette(&chunks, colors))
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Lbm, String> {
+    let (form, chunks) = chunks(bytes)?;
+    let bitmap = bitmap(&chunks)?;
+    let body = find(&chunks, b"BODY").ok_or("missing BODY chunk")?;
+    let chunky = form == b"PBM ";
+    if !chunky && !(1..=4).contains(&bitmap.planes) {
+        return Err(format!(
+            "only ILBMs of 1 to 4 planes are supported, not {}",
+            bitmap.planes
+        ));
+    }
+
+    let row_len = if chunky {
+        bitmap.width.next_multiple_of(2)
+    } else {
+        let planes = bitmap.planes + (bitmap.masking == MASK_PLANE) as usize;
+        bitmap.width.div_ceil(16) * 2 * planes
+    };
+    let len = row_len * bitmap.height;
+    let body = match bitmap.compression {
+        0 => body.to_vec(),
+        BYTERUN1 => unpack(body, len),
+        other => return Err(format!("unknown IFF compression {}", other)),
+    };
+
+    let mut grid = Grid::new(bitmap.width, bitmap.height);
+    for (y, row) in body.chunks(row_len).take(bitmap.height).enumerate() {
+        if chunky {
+            let len = bitmap.width.min(row.len());
+            grid.row_mut(y)[..len].copy_from_slice(&row[..len]);
+            continue;
+        }
+        //planes are interleaved by row, first plane in the low bit. A mask plane comes last
+        for (plane, plane_row) in row
+            .chunks(bitmap.width.div_ceil(16) * 2)
+            .take(bitmap.planes)
+            .enumerate()
+        {
+            for (x, pixel) in grid.row_mut(y).iter_mut().enumerate() {
+                let bit = plane_row.get(x / 8).map_or(0, |b| b >> (7 - x % 8) & 1);
+                *pixel |= bit << plane;
+            }
+        }
+    }
+    if grid.pixels().any(|&p| p as usize >= MAX_COLORS) {
+        return Err("only images of up to 16 colors are supported".to_owned());
+    }
+
+    let colors = if chunky {
+        MAX_COLORS
+    } else {
+        1 << bitmap.planes.min(4)
+    };
+    Ok(Lbm {
+        data: grid,
+        palette: read_palette(&chunks, colors),
+        ranges: chunks
+            .iter()
+            .filter(|(id, data)| *id == b"CRNG" && data.len() >= 8)
+            .map(|(_, crng)| {
+                let flags = i16::from_be_bytes([crng[4], crng[5]]);
+                ColorRange {
+                    rate: i16::from_be_bytes([crng[2], crng[3]]),
+                    active: flags & 1 != 0,
+                    reverse: flags & 2 != 0,
+                    low: crng[6],
+                    high: crng[7],
+                }
+            })
+            .collect(),
+    })
+}
+
+/// ByteRun1 compressed ILBM, with 2 planes if the image and palette fit in 4 colors, else 4
+pub fn encode(image_data: &RawGrid, palette: &ColorPalette, ranges: &[ColorRange]) -> Vec<u8> {
+    let cga = palette.len() <= 4 && image_data.pixels().all(|&p| p < 4);
+    let planes = if cga { 2 } else { 4 };
+    let (width, height) = (image_data.width(), image_data.height());
+
+    let mut bmhd = vec![];
+    bmhd.extend((width as u16).to_be_bytes());
+    bmhd.extend((height as u16).to_be_bytes());
+    bmhd.extend([0, 0, 0, 0]);
+    //planes, masking, compression, pad, transparent color, 5:6 pixel aspect of 320x200
+    bmhd.extend([planes as u8, 0, BYTERUN1, 0, 0, 0, 5, 6]);
+    bmhd.extend((width as u16).to_be_bytes());
+    bmhd.extend((height as u16).to_be_bytes());
+
+    let cmap: Vec<u8> = palette
+        .iter()
+        .flat_map(|c| c.rgb24().to_be_bytes()[1..].to_vec())
+        .collect();
+
+    let plane_len = width.div_ceil(16) * 2;
+    let mut body = vec![];
+    for row in image_data.rows() {
+        let mut line = vec![0u8; plane_len * planes];
+        for (x, pixel) in row.iter().enumerate() {
+            for plane in 0..planes {
+                line[plane * plane_len + x / 8] |= (pixel >> plane & 1) << (7 - x % 8);
+            }
+        }
+        body.extend(pack(&line));
+    }
+
+    let mut form = b"ILBM".to_vec();
+    write_chunk(&mut form, b"BMHD", &bmhd);
+    write_chunk(&mut form, b"CMAP", &cmap);
+    for range in ranges {
+        let flags = range.active as i16 | (range.reverse as i16) << 1;
+        let mut crng = vec![0, 0];
+        crng.extend(range.rate.to_be_bytes());
+        crng.extend(flags.to_be_bytes());
+        crng.extend([range.low, range.high]);
+        write_chunk(&mut form, b"CRNG", &crng);
+    }
+    write_chunk(&mut form, b"BODY", &body);
+
+    let mut bytes = vec![];
+    write_chunk(&mut bytes, b"FORM", &form);
+    bytes
+}
+
+pub fn is_lbm_path(path: &Path) -> bool {
+    crate::has_extension(path, &EXTENSIONS)
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend(id);
+    out.extend((data.len() as u32).to_be_bytes());
+    out.extend(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// ByteRun1: n of 0..=127 copies the next n+1 bytes, -1..=-127 repeats the next byte 1-n times
+fn unpack(data: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while out.len() < len && i < data.len() {
+        let n = data[i] as i8;
+        i += 1;
+        if n >= 0 {
+            let end = (i + n as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        } else if n != -128 {
+            let value = data.get(i).copied().unwrap_or(0);
+            out.extend(std::iter::repeat_n(value, 1 + n.unsigned_abs() as usize));
+            i += 1;
+        }
+    }
+    out.resize(len, 0);
+    out
+}
+
+/// Runs of 3 or more are repeated, anything else copied. Rows are packed separately, as
+/// Deluxe Paint expects
+fn pack(line: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut literal: Vec<u8> = vec![];
+    let mut i = 0;
+    let flush = |out: &mut Vec<u8>, literal: &mut Vec<u8>| {
+        for chunk in literal.chunks(128) {
+            out.push(chunk.len() as u8 - 1);
+            out.extend(chunk);
+        }
+        literal.clear();
+    };
+    while i < line.len() {
+        let run = line[i..]
+            .iter()
+            .take(128)
+            .take_while(|&&b| b == line[i])
+            .count();
+        if run >= 3 {
+            flush(&mut out, &mut literal);
+            out.extend([(1 - run as i16) as u8, line[i]]);
+            i += run;
+        } else {
+            literal.push(line[i]);
+            i += 1;
+        }
+    }
+    flush(&mut out, &mut literal);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_data::Raw;
+    use crate::parser::ParserType;
+
+    #[test]
+    fn byterun1() {
+        let line = [5, 5, 5, 5, 1, 2, 2, 9];
+        let packed = pack(&line);
+        assert_eq!(packed, vec![0xFD, 5, 3, 1, 2, 2, 9]);
+        assert_eq!(unpack(&packed, line.len()), line);
+        assert_eq!(unpack(&[0x80, 0xFF, 7], 2), vec![7, 7]);
+    }
+
+    #[test]
+    fn ilbm_round_trip() {
+        let data = Grid::from_flat((0..340).map(|i| (i / 3 % 16) as u8).collect(), 20);
+        let ranges = vec![ColorRange {
+            low: 4,
+            high: 7,
+            rate: 0x2000,
+            active: true,
+            reverse: false,
+        }];
+        let bytes = encode(&data, &palette::EGA0.to_vec(), &ranges);
+        assert_eq!(&bytes[8..12], b"ILBM");
+        assert_eq!(
+            decode(&bytes).unwrap(),
+            Lbm {
+                data,
+                palette: palette::EGA0.to_vec(),
+                ranges
+            }
+        );
+        assert_eq!(palette(&bytes).unwrap(), palette::EGA0.to_vec());
+    }
+
+    #[test]
+    fn cga_planes() {
+        let data = Grid::from_flat((0..24).map(|i| (i % 4) as u8).collect(), 8);
+        let bytes = encode(&data, &palette::CGA0.to_vec(), &[]);
+        let lbm = decode(&bytes).unwrap();
+        assert_eq!((lbm.data, lbm.palette), (data, palette::CGA0.to_vec()));
+
+        //a 24 bit image, and one with no planes at all
+        let mut bytes = bytes;
+        for planes in [24, 0] {
+            bytes[28] = planes;
+            assert!(!is_supported(&bytes));
+            assert!(decode(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn pbm() {
+        let mut bmhd = vec![0, 3, 0, 2, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 1, 1, 0, 3, 0, 2];
+        let mut form = b"PBM ".to_vec();
+        write_chunk(&mut form, b"BMHD", &bmhd);
+        //rows are padded to an even width
+        write_chunk(&mut form, b"BODY", &[1, 2, 3, 0, 4, 5, 6, 0]);
+        let mut bytes = vec![];
+        write_chunk(&mut bytes, b"FORM", &form);
+        let lbm = decode(&bytes).unwrap();
+        assert_eq!(lbm.data.to_vecs(), vec![vec![1, 2, 3], vec![4, 5, 6]]);
+        assert_eq!(lbm.palette, palette::EGA0.to_vec());
+
+        bmhd[2..4].copy_from_slice(&[0, 1]);
+        let mut form = b"PBM ".to_vec();
+        write_chunk(&mut form, b"BMHD", &bmhd);
+        write_chunk(&mut form, b"BODY", &[1, 200, 3, 0]);
+        let mut bytes = vec![];
+        write_chunk(&mut bytes, b"FORM", &form);
+        assert!(decode(&bytes).is_err());
+        assert!(ParserType::Lbm.decode(&bytes, 0).is_err());
+    }
+
+    #[test]
+    fn keeps_ranges() {
+        let data = Grid::from_flat((0..64).map(|i| (i % 16) as u8).collect(), 16);
+        let ranges = vec![ColorRange {
+            low: 1,
+            high: 3,
+            rate: 0x1000,
+            active: true,
+            reverse: true,
+        }];
+        let bytes = encode(&data, &palette::EGA0.to_vec(), &ranges);
+        let image = Raw::new(&bytes).parse(ParserType::Lbm, 0).unwrap();
+        assert_eq!(image.color_ranges, ranges);
+        assert_eq!(
+            decode(&ParserType::Lbm.encode(&image)).unwrap().ranges,
+            ranges
+        );
+    }
+}
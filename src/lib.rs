@@ -2,14 +2,39 @@
 
 use factor::factor::factor;
 
+pub mod bsave;
 pub mod color;
+pub mod compression;
+pub mod container;
+pub mod detect;
+pub mod egagraph;
+pub mod exe;
 pub mod file_data;
+pub mod grid;
 pub mod image;
+pub mod lbm;
 pub mod parser;
+pub mod pcx;
+pub mod registry;
+pub mod tilemap;
+
+#[cfg(feature = "formats")]
+pub mod description;
+
+#[cfg(feature = "scripting")]
+pub mod script;
 
 #[cfg(feature = "gui")]
 pub mod sdl;
 
+#[cfg(feature = "tiled")]
+pub mod tiled;
+
+#[cfg(feature = "sidecar")]
+pub mod preset;
+#[cfg(feature = "sidecar")]
+pub mod sidecar;
+
 #[cfg(feature = "terminal")]
 pub mod terminal;
 
@@ -19,8 +44,15 @@ pub mod png;
 #[cfg(feature = "wasm")]
 pub mod wasm;
 
-pub type RawGrid = Vec<Vec<u8>>;
-pub type Grid<T> = Vec<Vec<T>>;
+pub use grid::Grid;
+pub type RawGrid = Grid<u8>;
+
+/// Case insensitive check against a format's file extensions
+pub fn has_extension(path: &std::path::Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| extensions.iter().any(|x| x.eq_ignore_ascii_case(e)))
+}
 
 pub type Palette<T> = Vec<T>;
 pub type ColorPalette = Vec<crate::color::Color>;
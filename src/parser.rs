@@ -1,52 +1,199 @@
 use bitvec::prelude::*;
 
-use crate::{ImageType, RawGrid};
+use crate::image::Image;
+use crate::{lbm, pcx, registry};
+use crate::{ColorPalette, Grid, ImageType, RawGrid};
 
 #[cfg(feature = "png")]
 use crate::png;
+#[cfg(feature = "scripting")]
+use crate::script;
 
 //https://moddingwiki.shikadi.net/wiki/Raw_EGA_data#Row-planar_EGA_data
 #[derive(Debug, Clone, Copy)]
 pub struct EGARowPlanar;
 #[derive(Debug, Clone, Copy)]
 pub struct CGA;
-
+//https://moddingwiki.shikadi.net/wiki/Raw_EGA_data#Graphic-planar_EGA_data
+#[derive(Debug, Clone, Copy)]
+pub struct EGAPlanar;
+/// Two pixels to a byte, the first in the high nibble, as Ultima IV and V store their tiles
 #[derive(Debug, Clone, Copy)]
+pub struct EGAPacked;
+/// CGA video memory layout: even rows in the first 8k bank, odd rows in the second
+#[derive(Debug, Clone, Copy)]
+pub struct CGAInterlaced;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ParserType {
     CGA,
+    CGAInterlaced,
     EGARowPlanar,
+    EGAPlanar,
+    EGAPacked,
+    Pcx,
+    Lbm,
     #[cfg(feature = "png")]
     Png,
+    /// Runs the registered script, see [crate::script]
+    #[cfg(feature = "scripting")]
+    Script,
+    /// Added by a downstream crate, see [crate::registry]
+    Registered(usize),
 }
 
 impl ParserType {
+    /// Parsers for headerless data, which need a width to be guessed or given
+    pub const RAW: [ParserType; 5] = [
+        ParserType::CGA,
+        ParserType::CGAInterlaced,
+        ParserType::EGARowPlanar,
+        ParserType::EGAPlanar,
+        ParserType::EGAPacked,
+    ];
+
+    /// Each built in parser with its name and then any aliases
+    pub const BUILT_IN: &'static [(ParserType, &'static [&'static str])] = &[
+        (ParserType::CGA, &["cga"]),
+        (ParserType::CGAInterlaced, &["cga_interlaced", "cgi"]),
+        (
+            ParserType::EGARowPlanar,
+            &["ega_row_planar", "ega_row_parser", "erp"],
+        ),
+        (ParserType::EGAPlanar, &["ega_planar", "egp"]),
+        (ParserType::EGAPacked, &["ega_packed", "epk"]),
+        (ParserType::Pcx, &["pcx"]),
+        (ParserType::Lbm, &["lbm", "ilbm", "iff"]),
+        #[cfg(feature = "png")]
+        (ParserType::Png, &["png"]),
+        #[cfg(feature = "scripting")]
+        (ParserType::Script, &["script"]),
+    ];
+
     pub fn image_type(&self) -> ImageType {
         match self {
-            Self::EGARowPlanar => ImageType::EGA,
+            //pcx and lbm images are up to 16 colors, their palette says how many
+            Self::EGARowPlanar | Self::EGAPlanar | Self::EGAPacked | Self::Pcx | Self::Lbm => {
+                ImageType::EGA
+            }
+            #[cfg(feature = "scripting")]
+            Self::Script => ImageType::EGA,
+            Self::Registered(i) => registry::get(*i).parser.image_type(),
             _ => ImageType::CGA,
         }
     }
-    pub fn process_input(&self, buffer: &[u8], width: usize) -> RawGrid {
-        match self {
+    /// The pixels, with the palette and color cycling if the format carries them. Formats with
+    /// a header can fail to decode, and row planar data needs whole bytes for each plane of a row
+    pub fn decode(&self, buffer: &[u8], width: usize) -> Result<Image, String> {
+        let data = match self {
             Self::CGA => CGA.process_input(buffer, width),
+            Self::CGAInterlaced => CGAInterlaced.process_input(buffer, width),
+            Self::EGARowPlanar if width < 8 => {
+                return Err(format!("{} needs a width of at least 8", self.name()))
+            }
             Self::EGARowPlanar => EGARowPlanar.process_input(buffer, width),
+            Self::EGAPlanar => EGAPlanar.process_input(buffer, width),
+            Self::EGAPacked => EGAPacked.process_input(buffer, width),
+            Self::Pcx => {
+                let (data, palette) = pcx::decode(buffer)?;
+                return Ok(Image {
+                    parser: Some(*self),
+                    palette: Some(palette),
+                    ..Image::new(data)
+                });
+            }
+            Self::Lbm => {
+                let lbm = lbm::decode(buffer)?;
+                return Ok(Image {
+                    parser: Some(*self),
+                    palette: Some(lbm.palette),
+                    color_ranges: lbm.ranges,
+                    ..Image::new(lbm.data)
+                });
+            }
             #[cfg(feature = "png")]
             Self::Png => png::process_input(buffer),
+            #[cfg(feature = "scripting")]
+            Self::Script => {
+                let (data, palette) = script::registered()?.run(buffer, width)?;
+                return Ok(Image {
+                    parser: Some(*self),
+                    palette,
+                    ..Image::new(data)
+                });
+            }
+            Self::Registered(i) => registry::get(*i).parser.process_input(buffer, width),
+        };
+        Ok(Image {
+            parser: Some(*self),
+            ..Image::new(data)
+        })
+    }
+
+    /// The name first, then any aliases
+    pub fn names(&self) -> Vec<&'static str> {
+        match self {
+            Self::Registered(i) => registry::get(*i).names().collect(),
+            _ => Self::BUILT_IN
+                .iter()
+                .find(|(parser, _)| parser == self)
+                .map_or(vec![], |(_, names)| names.to_vec()),
         }
     }
 
-    pub fn type_str(str: &str) -> ParserType {
-        match str {
-            "ega_row_parser" | "erp" => ParserType::EGARowPlanar,
-            #[cfg(feature = "png")]
-            "png" => ParserType::Png,
-            _ => ParserType::CGA,
+    pub fn name(&self) -> &'static str {
+        self.names()[0]
+    }
+
+    pub fn type_str(str: &str) -> Result<ParserType, String> {
+        registry::parsers()
+            .into_iter()
+            .find(|parser| parser.names().contains(&str))
+            .ok_or(format!("unknown image parser: {}", str))
+    }
+
+    /// Formats that carry their own palette, which takes the place of the default for the image type
+    pub fn embeds_palette(&self) -> bool {
+        match self {
+            Self::Pcx | Self::Lbm => true,
+            #[cfg(feature = "scripting")]
+            Self::Script => true,
+            _ => false,
         }
     }
 
-    pub fn to_bytes(self, image_data: RawGrid) -> Vec<u8> {
-        //Placeholder only does CGA. Need to implement the others
-        CGA::to_bytes(image_data)
+    fn default_palette(&self, image_data: &RawGrid) -> ColorPalette {
+        if image_data.pixels().all(|&p| p < 4) {
+            crate::color::palette::CGA1.to_vec()
+        } else {
+            crate::color::palette::EGA0.to_vec()
+        }
+    }
+
+    /// The image in this format, with its palette and color cycling where the format holds them
+    pub fn encode(self, image: &Image) -> Vec<u8> {
+        match self {
+            Self::Pcx => pcx::encode(image.data(), &image.palette()),
+            Self::Lbm => lbm::encode(image.data(), &image.palette(), &image.color_ranges),
+            _ => self.to_bytes(image.data()),
+        }
+    }
+
+    pub fn to_bytes(self, image_data: &RawGrid) -> Vec<u8> {
+        match self {
+            Self::CGAInterlaced => CGAInterlaced::to_bytes(image_data),
+            Self::EGARowPlanar => EGARowPlanar::to_bytes(image_data),
+            Self::EGAPlanar => EGAPlanar::to_bytes(image_data),
+            Self::EGAPacked => EGAPacked::to_bytes(image_data),
+            Self::Pcx => pcx::encode(image_data, &self.default_palette(image_data)),
+            Self::Lbm => lbm::encode(image_data, &self.default_palette(image_data), &[]),
+            Self::Registered(i) => match registry::get(i).encoder {
+                Some(encode) => encode(image_data),
+                None => CGA::to_bytes(image_data),
+            },
+            //png and scripts have no raw encoding, so fall back to CGA
+            _ => CGA::to_bytes(image_data),
+        }
     }
 }
 
@@ -69,10 +216,7 @@ impl ProcessBinary for CGA {
     }
 
     fn process_input(&self, buffer: &[u8], width: usize) -> RawGrid {
-        CGA::words_to_bytes(buffer)
-            .chunks(width)
-            .map(|v| v.into())
-            .collect()
+        Grid::from_flat(CGA::words_to_bytes(buffer), width)
     }
 }
 
@@ -85,11 +229,11 @@ impl CGA {
             .collect()
     }
 
-    fn to_bytes(image_data: RawGrid) -> Vec<u8> {
+    fn to_bytes(image_data: &RawGrid) -> Vec<u8> {
         let bytes_per_new_byte = 8 / Self.word_size();
         image_data
-            .into_iter()
-            .flatten()
+            .pixels()
+            .copied()
             .collect::<Vec<_>>()
             .chunks(bytes_per_new_byte)
             .map(Self::compress_bytes_to_words)
@@ -105,24 +249,138 @@ impl CGA {
     }
 }
 
+impl ProcessBinary for CGAInterlaced {
+    fn image_type(&self) -> ImageType {
+        ImageType::CGA
+    }
+
+    fn process_input(&self, buffer: &[u8], width: usize) -> RawGrid {
+        //rows start on a byte, so a width that isn't a multiple of 4 leaves unused pixels
+        let row_bytes = width.div_ceil(self.pixels_per_byte()).max(1);
+        let (even, odd) = buffer.split_at(buffer.len().min(Self::BANK));
+        let bank_rows = |bank: &[u8]| (bank.len() / row_bytes).min(Self::BANK_ROWS);
+        let (even_rows, odd_rows) = (bank_rows(even), bank_rows(odd));
+        //rows alternate banks, so stop at the first one missing from either
+        let rows = 2 * even_rows.min(odd_rows) + (even_rows > odd_rows) as usize;
+        let mut pixels = Vec::with_capacity(rows * width);
+        for y in 0..rows {
+            let bank = if y % 2 == 0 { even } else { odd };
+            let start = y / 2 * row_bytes;
+            let row = CGA::words_to_bytes(&bank[start..start + row_bytes]);
+            pixels.extend_from_slice(&row[..width]);
+        }
+        Grid::from_flat(pixels, width)
+    }
+}
+
+impl CGAInterlaced {
+    pub const BANK: usize = 0x2000;
+    /// A 200 line screen has 100 in each bank, leaving the end of each bank unused
+    pub const BANK_ROWS: usize = 100;
+
+    /// Each bank is padded to its full 8k, as in video memory
+    fn to_bytes(image_data: &RawGrid) -> Vec<u8> {
+        let mut banks = [vec![], vec![]];
+        for (y, row) in image_data.rows().enumerate() {
+            banks[y % 2].extend(CGA::to_bytes(&Grid::from_flat(row.to_vec(), row.len())));
+        }
+        for bank in banks.iter_mut() {
+            let len = bank.len().next_multiple_of(Self::BANK).max(Self::BANK);
+            bank.resize(len, 0);
+        }
+        banks.concat()
+    }
+}
+
+impl ProcessBinary for EGAPlanar {
+    fn image_type(&self) -> ImageType {
+        ImageType::EGA
+    }
+
+    fn process_input(&self, buffer: &[u8], width: usize) -> RawGrid {
+        let plane_len = buffer.len() / 4;
+        if plane_len == 0 {
+            return Grid::from_flat(vec![], width);
+        }
+        let mut pixels = vec![0u8; plane_len * 8];
+        for plane in buffer.chunks_exact(plane_len.max(1)).take(4) {
+            for (i, b) in plane.view_bits::<Msb0>().iter().by_vals().enumerate() {
+                pixels[i] = pixels[i] << 1 | b as u8;
+            }
+        }
+        Grid::from_flat(pixels, width)
+    }
+}
+
+impl EGAPlanar {
+    fn to_bytes(image_data: &RawGrid) -> Vec<u8> {
+        let pixels: Vec<u8> = image_data.pixels().copied().collect();
+        (0..4).rev().flat_map(|bit| planes(&pixels, bit)).collect()
+    }
+}
+
+/// One bit of every pixel, packed 8 to a byte with the first pixel in the high bit
+fn planes(pixels: &[u8], bit: usize) -> Vec<u8> {
+    let mut plane = bitvec![u8, Msb0; 0; pixels.len().next_multiple_of(8)];
+    for (i, pixel) in pixels.iter().enumerate() {
+        plane.set(i, pixel >> bit & 1 == 1);
+    }
+    plane.into_vec()
+}
+
+impl ProcessBinary for EGAPacked {
+    fn image_type(&self) -> ImageType {
+        ImageType::EGA
+    }
+
+    fn process_input(&self, buffer: &[u8], width: usize) -> RawGrid {
+        let pixels = buffer.iter().flat_map(|b| [b >> 4, b & 0x0F]).collect();
+        Grid::from_flat(pixels, width)
+    }
+}
+
+impl EGAPacked {
+    fn to_bytes(image_data: &RawGrid) -> Vec<u8> {
+        let pixels: Vec<u8> = image_data.pixels().copied().collect();
+        pixels
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair.get(1).unwrap_or(&0) & 0x0F)
+            .collect()
+    }
+}
+
 impl ProcessBinary for EGARowPlanar {
     fn image_type(&self) -> ImageType {
         ImageType::EGA
     }
 
     fn process_input(&self, buffer: &[u8], width: usize) -> RawGrid {
+        //a row narrower than 8 pixels has no whole byte for each plane, see ParserType::decode
         if width < 8 {
-            //TODO don't know if the spec supports this due to row planar. Maybe smarter handling of row chunking
-            panic!("This parser cannot handle width less than 8")
+            return Grid::from_flat(vec![], width);
         }
-        buffer
-            .chunks(width / self.pixels_per_byte())
-            .map(|row| self.words_to_bytes_row(row))
-            .collect()
+        let row_bytes = width / self.pixels_per_byte();
+        let pixels = buffer
+            .chunks(row_bytes)
+            .flat_map(|row| {
+                //a partial last row is padded out, a short one has no whole plane to read
+                let mut row = row.to_vec();
+                row.resize(row_bytes, 0);
+                self.words_to_bytes_row(&row)
+            })
+            .collect();
+        Grid::from_flat(pixels, width)
     }
 }
 
 impl EGARowPlanar {
+    fn to_bytes(image_data: &RawGrid) -> Vec<u8> {
+        image_data
+            .rows()
+            .flat_map(|row| (0..4).rev().flat_map(|bit| planes(row, bit)))
+            .collect()
+    }
+
     fn words_to_bytes_row(&self, buffer: &[u8]) -> Vec<u8> {
         let width = buffer.len() * 2;
         let mut nv: Vec<u8> = vec![0; width];
@@ -138,6 +396,7 @@ impl EGARowPlanar {
 
 #[cfg(test)]
 mod tests {
+    use crate::color::palette;
     use crate::parser::*;
 
     #[test]
@@ -156,7 +415,7 @@ mod tests {
     fn test_ega_row_planar_process_input() {
         let data: u128 = 0xFF_FF_FF_FF_FD_7F_F6_9F_F6_9F_FD_7F_FF_FF_FF_FF;
         assert_eq!(
-            EGARowPlanar.process_input(&data.to_be_bytes(), 8),
+            EGARowPlanar.process_input(&data.to_be_bytes(), 8).to_vecs(),
             vec!(
                 vec!(15, 15, 15, 15, 15, 15, 15, 15),
                 vec!(11, 14, 14, 15, 13, 15, 7, 13),
@@ -165,11 +424,78 @@ mod tests {
             )
         );
     }
+    #[test]
+    fn test_decode_errors() {
+        assert!(ParserType::Pcx.decode(&[0x0A, 5, 1, 8], 0).is_err());
+        assert!(ParserType::EGARowPlanar.decode(&[0; 8], 4).is_err());
+        assert_eq!(EGARowPlanar.process_input(&[0; 8], 4).height(), 0);
+        let image = ParserType::Pcx
+            .decode(&pcx::encode(&Grid::new(4, 1), &palette::CGA0.to_vec()), 0)
+            .unwrap();
+        assert_eq!(image.palette, Some(palette::CGA0.to_vec()));
+    }
+
+    #[test]
+    fn test_ega_round_trips() {
+        let data = Grid::from_flat((0..64).map(|i| (i * 7 % 16) as u8).collect(), 16);
+        for parser in [
+            ParserType::EGARowPlanar,
+            ParserType::EGAPlanar,
+            ParserType::EGAPacked,
+        ] {
+            let bytes = parser.to_bytes(&data);
+            assert_eq!(bytes.len(), 32);
+            assert_eq!(
+                parser.decode(&bytes, 16).unwrap().data,
+                data,
+                "{}",
+                parser.name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_ega_planar_process_input() {
+        //planes are stored one after another, first plane in the high bit
+        let buffer = [0b1000_0000, 0, 0b1100_0000, 0, 0, 0, 0b0000_0001, 0];
+        assert_eq!(
+            EGAPlanar.process_input(&buffer, 16).to_vecs(),
+            vec!(vec!(12, 4, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0)),
+        );
+        assert_eq!(EGAPlanar.process_input(&[0xFF; 3], 8).height(), 0);
+    }
+
+    #[test]
+    fn test_cga_interlaced() {
+        //4 pixel rows, each the row number
+        let data = Grid::from((0..6u8).map(|y| vec![y % 4; 4]).collect::<Vec<_>>());
+        let bytes = ParserType::CGAInterlaced.to_bytes(&data);
+        assert_eq!(bytes.len(), 2 * CGAInterlaced::BANK);
+        assert_eq!(&bytes[..3], &[0x00, 0xAA, 0x00]);
+        assert_eq!(&bytes[CGAInterlaced::BANK..][..3], &[0x55, 0xFF, 0x55]);
+        let decoded = CGAInterlaced.process_input(&bytes, 4);
+        assert_eq!(decoded.view(0, 0, 4, 6).to_grid(), data);
+
+        //only the even bank, and a width leaving unused pixels at the end of each row
+        let short = CGAInterlaced.process_input(&[0x1B; 200], 6);
+        assert_eq!(short.height(), 1);
+        assert_eq!(short.row(0), &[0, 1, 2, 3, 0, 1]);
+        let odd = Grid::from((0..3u8).map(|y| vec![y; 6]).collect::<Vec<_>>());
+        let bytes = ParserType::CGAInterlaced.to_bytes(&odd);
+        assert_eq!(
+            CGAInterlaced
+                .process_input(&bytes, 6)
+                .view(0, 0, 6, 3)
+                .to_grid(),
+            odd
+        );
+    }
+
     #[test]
     fn test_cga_process_input() {
         let data: u128 = 0xFF_FF_FF_FF_FD_7F_F6_9F_F6_9F_FD_7F_FF_FF_FF_FF;
         assert_eq!(
-            CGA.process_input(&data.to_be_bytes(), 8),
+            CGA.process_input(&data.to_be_bytes(), 8).to_vecs(),
             vec!(
                 vec!(3, 3, 3, 3, 3, 3, 3, 3),
                 vec!(3, 3, 3, 3, 3, 3, 3, 3),
@@ -196,7 +522,7 @@ mod tests {
             vec![3, 3, 3, 3, 3, 3, 3, 3],
         ];
         assert_eq!(
-            CGA::to_bytes(data),
+            CGA::to_bytes(&Grid::from(data)),
             0xFF_FF_FF_FF_FD_7F_F6_9F_F6_9F_FD_7F_FF_FF_FF_FFu128.to_be_bytes()
         );
     }
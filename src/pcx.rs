@@ -0,0 +1,355 @@
+//! ZSoft PCX, the standard paint format of the era: a 128 byte header including a 16 color palette,
+//! followed by run length encoded scanlines that each hold every plane of the row in turn.
+//! 2 color, 4 color (CGA, 2 bits per pixel) and 16 color (EGA, 4 planes) images are supported
+//! https://moddingwiki.shikadi.net/wiki/PCX_Format
+
+use std::path::Path;
+
+use bitvec::prelude::*;
+
+use crate::color::{palette, Color};
+use crate::{ColorPalette, Grid, RawGrid};
+
+pub const MANUFACTURER: u8 = 0x0A;
+pub const HEADER_LEN: usize = 128;
+pub const EXTENSIONS: [&str; 1] = ["pcx"];
+const VERSION: u8 = 5;
+/// PC Paintbrush 2.8 without palette information
+const NO_PALETTE_VERSION: u8 = 3;
+const CGA_PALETTE: u8 = 0x40;
+const CGA_INTENSITY: u8 = 0x20;
+const RLE: u8 = 1;
+const RUN: u8 = 0xC0;
+const MAX_RUN: usize = 0x3F;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub bits_per_pixel: u8,
+    pub x_min: u16,
+    pub y_min: u16,
+    pub x_max: u16,
+    pub y_max: u16,
+    pub palette: [u8; 48],
+    pub planes: u8,
+    pub bytes_per_line: u16,
+}
+
+impl Header {
+    pub fn read(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < HEADER_LEN || bytes[0] != MANUFACTURER {
+            return Err("not a PCX file".to_owned());
+        }
+        if bytes[2] != RLE {
+            return Err(format!("unknown PCX encoding {}", bytes[2]));
+        }
+        let word = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let header = Self {
+            version: bytes[1],
+            bits_per_pixel: bytes[3],
+            x_min: word(4),
+            y_min: word(6),
+            x_max: word(8),
+            y_max: word(10),
+            palette: bytes[16..64].try_into().unwrap(),
+            planes: bytes[65],
+            bytes_per_line: word(66),
+        };
+        if !matches!(header.bits_per_pixel, 1 | 2 | 4) || header.bits() > 4 || header.planes == 0 {
+            return Err(format!(
+                "unsupported PCX layout: {} bits per pixel in {} planes",
+                header.bits_per_pixel, header.planes
+            ));
+        }
+        Ok(header)
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..4].copy_from_slice(&[MANUFACTURER, self.version, RLE, self.bits_per_pixel]);
+        for (i, word) in [self.x_min, self.y_min, self.x_max, self.y_max]
+            .iter()
+            .enumerate()
+        {
+            bytes[4 + i * 2..6 + i * 2].copy_from_slice(&word.to_le_bytes());
+        }
+        bytes[16..64].copy_from_slice(&self.palette);
+        bytes[65] = self.planes;
+        bytes[66..68].copy_from_slice(&self.bytes_per_line.to_le_bytes());
+        //color rather than grayscale
+        bytes[68] = 1;
+        bytes
+    }
+
+    pub fn width(&self) -> usize {
+        (self.x_max as usize + 1).saturating_sub(self.x_min as usize)
+    }
+
+    pub fn height(&self) -> usize {
+        (self.y_max as usize + 1).saturating_sub(self.y_min as usize)
+    }
+
+    /// Bits per pixel across all planes
+    fn bits(&self) -> usize {
+        self.bits_per_pixel as usize * self.planes as usize
+    }
+
+    /// The header palette. 4 color images store the CGA background and palette choice in it,
+    /// see [cga_palette]; others store RGB, each entry mapped to the nearest of the 16 colors.
+    /// Version 3 files have no palette, and get the default CGA or EGA palette
+    pub fn palette(&self) -> ColorPalette {
+        let cga = self.bits() <= 2;
+        if self.version == NO_PALETTE_VERSION || !cga && self.palette.iter().all(|&b| b == 0) {
+            return if cga {
+                palette::CGA1.to_vec()
+            } else {
+                palette::EGA0.to_vec()
+            };
+        }
+        if cga {
+            return cga_palette(&self.palette);
+        }
+        self.palette
+            .chunks(3)
+            .take(16)
+            .map(|rgb| Color::nearest(u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]])))
+            .collect()
+    }
+}
+
+/// PC Paintbrush's CGA form: the background color in the high nibble of the first byte, and in
+/// the fourth the palette (bit 6, set for cyan, magenta and white rather than green, red and
+/// brown) and intensity (bit 5)
+pub fn cga_palette(header_palette: &[u8; 48]) -> ColorPalette {
+    let background = palette::EGA0[(header_palette[0] >> 4) as usize].clone();
+    let bright = header_palette[3] & CGA_INTENSITY != 0;
+    let colors: [fn(bool) -> Color; 3] = if header_palette[3] & CGA_PALETTE != 0 {
+        [Color::Cyan, Color::Magenta, Color::White]
+    } else {
+        [Color::Green, Color::Red, Color::Brown]
+    };
+    std::iter::once(background)
+        .chain(colors.map(|color| color(bright)))
+        .collect()
+}
+
+/// The CGA form of a palette, if it is one the CGA can show
+fn cga_header_palette(palette: &ColorPalette) -> Option<[u8; 48]> {
+    let background = palette::EGA0
+        .iter()
+        .position(|c| Some(c) == palette.first())?;
+    let mut header_palette = [0u8; 48];
+    header_palette[0] = (background as u8) << 4;
+    [0, CGA_PALETTE]
+        .into_iter()
+        .flat_map(|choice| [choice, choice | CGA_INTENSITY])
+        .find_map(|flags| {
+            header_palette[3] = flags;
+            (cga_palette(&header_palette)[1..] == palette[1..]).then_some(header_palette)
+        })
+}
+
+/// Decode to pixels and the file's palette
+pub fn decode(bytes: &[u8]) -> Result<(RawGrid, ColorPalette), String> {
+    let header = Header::read(bytes)?;
+    let (width, height) = (header.width(), header.height());
+    let line_len = header.bytes_per_line as usize;
+    let scanline = line_len * header.planes as usize;
+    let bpp = header.bits_per_pixel as usize;
+    if line_len * 8 / bpp < width {
+        return Err("PCX lines are too short for the image width".to_owned());
+    }
+    //every two bytes of data unpack to at most a full run, so a bigger image can't be in the file
+    let max_len = (bytes.len() - HEADER_LEN).div_ceil(2) * MAX_RUN;
+    if scanline * height > max_len {
+        return Err(format!(
+            "the PCX header says {}x{} but the file only holds {} bytes of pixels",
+            width, height, max_len
+        ));
+    }
+
+    let data = unpack(&bytes[HEADER_LEN..], scanline * height);
+    let mut grid = Grid::new(width, height);
+    for (y, line) in data.chunks(scanline).enumerate() {
+        let row = grid.row_mut(y);
+        for (plane, plane_bytes) in line.chunks(line_len).enumerate() {
+            let values = plane_bytes.view_bits::<Msb0>().chunks(bpp);
+            for (pixel, value) in row.iter_mut().zip(values) {
+                *pixel |= value.load_be::<u8>() << (plane * bpp);
+            }
+        }
+    }
+    Ok((grid, header.palette()))
+}
+
+/// Encode as 2 bits per pixel if the image fits in 4 colors of a CGA palette, otherwise as 4 planes
+pub fn encode(image_data: &RawGrid, palette: &ColorPalette) -> Vec<u8> {
+    let cga = (palette.len() == 4 && image_data.pixels().all(|&p| p < 4))
+        .then(|| cga_header_palette(palette))
+        .flatten();
+    let (bpp, planes) = if cga.is_some() { (2, 1) } else { (1, 4) };
+    let line_len = (image_data.width() * bpp).div_ceil(8).next_multiple_of(2);
+
+    let header_palette = cga.unwrap_or_else(|| {
+        let mut header_palette = [0u8; 48];
+        for (rgb, color) in header_palette.chunks_mut(3).zip(palette) {
+            rgb.copy_from_slice(&color.rgb24().to_be_bytes()[1..]);
+        }
+        header_palette
+    });
+    let header = Header {
+        version: VERSION,
+        bits_per_pixel: bpp as u8,
+        x_min: 0,
+        y_min: 0,
+        x_max: image_data.width().saturating_sub(1) as u16,
+        y_max: image_data.height().saturating_sub(1) as u16,
+        palette: header_palette,
+        planes: planes as u8,
+        bytes_per_line: line_len as u16,
+    };
+
+    let mut bytes = header.to_bytes().to_vec();
+    for row in image_data.rows() {
+        let mut line = Vec::with_capacity(line_len * planes);
+        for plane in 0..planes {
+            let mut bits = bitvec![u8, Msb0; 0; line_len * 8];
+            for (x, pixel) in row.iter().enumerate() {
+                let value = pixel >> (plane * bpp) & ((1 << bpp) - 1);
+                bits[x * bpp..(x + 1) * bpp].store_be(value);
+            }
+            line.extend(bits.into_vec());
+        }
+        bytes.extend(pack(&line));
+    }
+    bytes
+}
+
+pub fn is_pcx_path(path: &Path) -> bool {
+    crate::has_extension(path, &EXTENSIONS)
+}
+
+/// A byte with the top two bits set repeats the next byte (its low 6 bits) times
+fn unpack(data: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut bytes = data.iter();
+    while out.len() < len {
+        let Some(&byte) = bytes.next() else { break };
+        if byte & RUN == RUN {
+            let value = bytes.next().copied().unwrap_or(0);
+            out.extend(std::iter::repeat_n(value, (byte & !RUN) as usize));
+        } else {
+            out.push(byte);
+        }
+    }
+    out.resize(len, 0);
+    out
+}
+
+/// Runs never cross a scanline, and single bytes that look like run counts are written as runs
+fn pack(line: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < line.len() {
+        let value = line[i];
+        let run = line[i..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|&&b| b == value)
+            .count();
+        if run > 1 || value & RUN == RUN {
+            out.extend([RUN | run as u8, value]);
+        } else {
+            out.push(value);
+        }
+        i += run;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rle() {
+        let line = [1, 1, 1, 0xC5, 2, 3, 3];
+        let packed = pack(&line);
+        assert_eq!(packed, vec![0xC3, 1, 0xC1, 0xC5, 2, 0xC2, 3]);
+        assert_eq!(unpack(&packed, line.len()), line);
+        assert_eq!(pack(&[9; 70]), vec![0xFF, 9, 0xC7, 9]);
+    }
+
+    #[test]
+    fn cga_round_trip() {
+        let data = Grid::from_flat((0..60).map(|i| (i % 7 % 4) as u8).collect(), 10);
+        let palette = palette::CGA0.to_vec();
+        let bytes = encode(&data, &palette);
+        assert_eq!(bytes[3], 2);
+        assert_eq!(bytes[65], 1);
+        assert_eq!(decode(&bytes).unwrap(), (data, palette));
+    }
+
+    #[test]
+    fn ega_round_trip() {
+        let data = Grid::from_flat((0..60).map(|i| (i * 5 % 16) as u8).collect(), 12);
+        let palette = palette::EGA0.to_vec();
+        let bytes = encode(&data, &palette);
+        assert_eq!((bytes[3], bytes[65]), (1, 4));
+        assert_eq!(decode(&bytes).unwrap(), (data, palette));
+    }
+
+    #[test]
+    fn hostile_header() {
+        let mut bytes = encode(&Grid::new(4, 1), &palette::EGA0.to_vec());
+        bytes[8..12].fill(0xFF);
+        bytes[66..68].fill(0xFF);
+        assert!(decode(&bytes).unwrap_err().contains("65536x65536"));
+    }
+
+    #[test]
+    fn blank_palette() {
+        let mut bytes = encode(&Grid::new(4, 1), &palette::CGA0.to_vec());
+        bytes[1] = NO_PALETTE_VERSION;
+        assert_eq!(decode(&bytes).unwrap().1, palette::CGA1.to_vec());
+        let mut bytes = encode(&Grid::new(4, 1), &palette::EGA0.to_vec());
+        bytes[16..64].fill(0);
+        assert_eq!(decode(&bytes).unwrap().1, palette::EGA0.to_vec());
+    }
+
+    #[test]
+    fn cga_header() {
+        //as PC Paintbrush saves a 320x200 4 color screen: blue background, bright palette 0
+        let mut bytes = vec![0u8; HEADER_LEN];
+        bytes[..4].copy_from_slice(&[MANUFACTURER, VERSION, RLE, 2]);
+        bytes[8..12].copy_from_slice(&[0x3F, 0x01, 0xC7, 0x00]);
+        bytes[16..20].copy_from_slice(&[0x10, 0x00, 0x00, 0x20]);
+        bytes[65] = 1;
+        bytes[66] = 80;
+        let header = Header::read(&bytes).unwrap();
+        assert_eq!((header.width(), header.height()), (320, 200));
+        assert_eq!(
+            header.palette(),
+            vec![
+                Color::Blue(false),
+                Color::Green(true),
+                Color::Red(true),
+                Color::Brown(true)
+            ]
+        );
+        bytes[19] = 0x40;
+        assert_eq!(
+            Header::read(&bytes).unwrap().palette()[1..],
+            palette::CGA1[1..]
+        );
+
+        //a palette the CGA can't show is saved as 16 colors
+        let odd = vec![
+            Color::Black(false),
+            Color::Blue(false),
+            Color::Red(true),
+            Color::White(true),
+        ];
+        assert_eq!(encode(&Grid::new(4, 1), &odd)[3], 1);
+    }
+}
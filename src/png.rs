@@ -4,7 +4,8 @@ use std::path::PathBuf;
 
 use image::{Rgb, RgbImage};
 
-use crate::{ColorPalette, RawGrid};
+use crate::image::Image;
+use crate::{ColorPalette, Grid, RawGrid};
 
 impl crate::color::Color {
     pub fn to_rgb(&self) -> Rgb<u8> {
@@ -17,42 +18,144 @@ pub fn process_input(buffer: &[u8]) -> RawGrid {
     let mut reader = ImageReader::new(Cursor::new(buffer));
     reader.set_format(image::ImageFormat::Png);
     let img = reader.decode().unwrap().to_rgb8();
-    img.rows()
-        .map(|row| {
-            row.map(|pixel| match pixel.0 {
-                [0, 0, 0] => 0,
-                [85, 255, 255] => 1,
-                [255, 85, 255] => 2,
-                [255, 255, 255] => 3,
-                _ => 4,
-            })
-            .collect()
+    let pixels = img
+        .pixels()
+        .map(|pixel| match pixel.0 {
+            [0, 0, 0] => 0,
+            [85, 255, 255] => 1,
+            [255, 85, 255] => 2,
+            [255, 255, 255] => 3,
+            _ => 4,
         })
-        .collect()
+        .collect();
+    Grid::from_flat(pixels, img.width() as usize)
 }
 
-pub fn convert_image(image_data: RawGrid, palette: ColorPalette) -> RgbImage {
-    let mut img = RgbImage::new(image_data[0].len() as u32, image_data.len() as u32);
-    for (x, row) in image_data.iter().enumerate() {
-        for (y, index) in row.iter().enumerate() {
-            img.put_pixel(y as u32, x as u32, palette[*index as usize].to_rgb());
+pub fn convert_image(image_data: &RawGrid, palette: &ColorPalette) -> RgbImage {
+    let mut img = RgbImage::new(image_data.width() as u32, image_data.height() as u32);
+    for (y, row) in image_data.rows().enumerate() {
+        for (x, index) in row.iter().enumerate() {
+            img.put_pixel(x as u32, y as u32, palette[*index as usize].to_rgb());
         }
     }
     img
 }
 
-pub fn save(
-    path: PathBuf,
-    image_data: RawGrid,
-    palette: ColorPalette,
-) -> Result<(), image::ImageError> {
-    convert_image(image_data, palette).save(path)
+/// Saves in the format matching the extension. PNGs get the image's provenance as a tEXt chunk
+pub fn save(path: PathBuf, image: &Image) -> Result<(), image::ImageError> {
+    let is_png = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    if is_png {
+        let mut bytes = Vec::new();
+        write_to(&mut bytes, image.data(), &image.palette())?;
+        let bytes = insert_text_chunk(bytes, "cega", &image.provenance());
+        std::fs::write(path, bytes).map_err(image::ImageError::IoError)
+    } else {
+        convert_image(image.data(), &image.palette()).save(path)
+    }
 }
 
 pub fn write_to(
     bytes: &mut Vec<u8>,
-    image_data: RawGrid,
-    palette: ColorPalette,
+    image_data: &RawGrid,
+    palette: &ColorPalette,
 ) -> Result<(), image::ImageError> {
     convert_image(image_data, palette).write_to(&mut Cursor::new(bytes), image::ImageFormat::Png)
 }
+
+//no CGA or EGA color, so it stands out on all of them
+const HIGHLIGHT: Rgb<u8> = Rgb([255, 128, 0]);
+
+/// The image with rectangles (x, y, width, height) outlined over it, to show where things are
+pub fn annotate(
+    image_data: &RawGrid,
+    palette: &ColorPalette,
+    boxes: &[(usize, usize, usize, usize)],
+) -> RgbImage {
+    let mut img = convert_image(image_data, palette);
+    let mut put = |x: usize, y: usize| {
+        if x < image_data.width() && y < image_data.height() {
+            img.put_pixel(x as u32, y as u32, HIGHLIGHT);
+        }
+    };
+    for &(x, y, width, height) in boxes {
+        let (right, bottom) = (x + width.max(1) - 1, y + height.max(1) - 1);
+        for i in x..=right {
+            put(i, y);
+            put(i, bottom);
+        }
+        for j in y..=bottom {
+            put(x, j);
+            put(right, j);
+        }
+    }
+    img
+}
+
+//PNG chunks are length, type, data, crc. IHDR is always first: 8 byte signature + 25 byte chunk
+fn insert_text_chunk(mut png: Vec<u8>, keyword: &str, text: &str) -> Vec<u8> {
+    let mut chunk = b"tEXt".to_vec();
+    chunk.extend(keyword.as_bytes());
+    chunk.push(0);
+    chunk.extend(text.as_bytes());
+
+    let mut encoded = ((chunk.len() - 4) as u32).to_be_bytes().to_vec();
+    encoded.extend(&chunk);
+    encoded.extend(crc32(&chunk).to_be_bytes());
+    png.splice(33..33, encoded);
+    png
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |c, _| {
+            if c & 1 == 1 {
+                (c >> 1) ^ 0xEDB8_8320
+            } else {
+                c >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    }
+
+    #[test]
+    fn annotates() {
+        let grid = Grid::new(4, 3);
+        let img = annotate(
+            &grid,
+            &crate::color::palette::CGA1.to_vec(),
+            &[(1, 0, 3, 3)],
+        );
+        assert_eq!(img.get_pixel(1, 0), &HIGHLIGHT);
+        assert_eq!(img.get_pixel(3, 2), &HIGHLIGHT);
+        assert_eq!(img.get_pixel(2, 1), &Rgb([0, 0, 0]));
+        assert_eq!(img.get_pixel(0, 1), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn provenance_chunk() {
+        let mut image = Image::new(Grid::from(vec![vec![0, 1], vec![2, 3]]));
+        image.width = 2;
+        let mut bytes = Vec::new();
+        write_to(&mut bytes, image.data(), &image.palette()).unwrap();
+        let bytes = insert_text_chunk(bytes, "cega", &image.provenance());
+
+        //decoding validates the chunk crc
+        assert_eq!(
+            process_input(&bytes).to_vecs(),
+            vec![vec![0, 4], vec![4, 4]]
+        );
+        let text = b"tEXtcega\0parser=unknown width=2 offset=0";
+        assert!(bytes.windows(text.len()).any(|w| w == text));
+    }
+}
@@ -0,0 +1,141 @@
+//! Known game graphics files and the decode settings that work for them, bundled from
+//! `presets.json`. Files are recognized by name (or `*.EXT`), and optionally size and CRC-32
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sidecar::DecodeParams;
+
+const DATABASE: &str = include_str!("presets.json");
+pub const AUTO: &str = "auto";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KnownFile {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32: Option<u32>,
+}
+
+impl KnownFile {
+    fn matches(&self, name: &str, bytes: &[u8]) -> bool {
+        let name = name.to_ascii_uppercase();
+        let pattern = self.name.to_ascii_uppercase();
+        let name_matches = match pattern.strip_prefix('*') {
+            Some(extension) => name.ends_with(extension),
+            None => name == pattern,
+        };
+        name_matches
+            && self.size.is_none_or(|size| size == bytes.len())
+            && self.crc32.is_none_or(|crc| crc == crc32(bytes))
+    }
+
+    /// Hashes over sizes over exact names over patterns
+    fn specificity(&self) -> usize {
+        self.crc32.map_or(0, |_| 4)
+            + self.size.map_or(0, |_| 2)
+            + !self.name.starts_with('*') as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub files: Vec<KnownFile>,
+    pub params: DecodeParams,
+}
+
+impl Preset {
+    pub fn all() -> Vec<Preset> {
+        serde_json::from_str(DATABASE).expect("presets.json is checked by the tests")
+    }
+
+    pub fn find(name: &str) -> Result<Preset, String> {
+        Self::all()
+            .into_iter()
+            .find(|p| p.name == name)
+            .ok_or(format!("unknown preset: {}", name))
+    }
+
+    /// The preset that most specifically matches the file
+    pub fn lookup(path: &Path, bytes: &[u8]) -> Option<Preset> {
+        let name = path.file_name()?.to_string_lossy();
+        Self::all()
+            .into_iter()
+            .filter_map(|preset| {
+                let best = preset
+                    .files
+                    .iter()
+                    .filter(|file| file.matches(&name, bytes))
+                    .map(KnownFile::specificity)
+                    .max()?;
+                Some((best, preset))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, preset)| preset)
+    }
+}
+
+/// The common CRC-32 (as zip and png use), for telling apart files of the same name and size
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{Compression, Lzw};
+    use crate::file_data::Raw;
+    use crate::parser::ParserType;
+    use crate::Grid;
+
+    #[test]
+    fn presets_are_valid() {
+        for preset in Preset::all() {
+            assert!(preset.params.parser().is_ok(), "{}", preset.name);
+            assert!(preset.params.compression().is_ok(), "{}", preset.name);
+            assert_ne!(preset.name, AUTO);
+        }
+        assert!(Preset::find("ultima9").is_err());
+    }
+
+    #[test]
+    fn lookup() {
+        let name =
+            |path: &str, bytes: &[u8]| Preset::lookup(Path::new(path), bytes).map(|p| p.name);
+        assert_eq!(
+            name("u4/shapes.ega", &[0; 32768]).as_deref(),
+            Some("ultima4_tiles")
+        );
+        //the wrong size falls back to the extension
+        assert_eq!(
+            name("SHAPES.EGA", &[0; 100]).as_deref(),
+            Some("ultima4_screen")
+        );
+        assert_eq!(name("TILES.16", &[]).as_deref(), Some("ultima5_tiles"));
+        assert_eq!(name("TILES.32", &[]), None);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn ultima5_tiles() {
+        let tiles = Grid::from_flat((0..1024).map(|i| (i / 16 % 16) as u8).collect(), 16);
+        let bytes = ParserType::EGAPacked.to_bytes(&tiles);
+        let raw = Raw::new(&Compression::Lzw(Lzw).compress(&bytes));
+        let image = Preset::find("ultima5_tiles")
+            .unwrap()
+            .params
+            .decode(&raw)
+            .unwrap();
+        assert_eq!(image.data().width(), 64);
+        assert_eq!(image.data().row(0), &[0; 16].repeat(4)[..]);
+    }
+}
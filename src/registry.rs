@@ -0,0 +1,176 @@
+//! Every image parser cega knows by name: the built in [ParserType]s, and any a downstream crate
+//! adds with [register]. The CLI's `-i` list and format detection enumerate what
+//! is here, and the wasm parser menu the [raw] parsers
+//!
+//! ```
+//! use cega::parser::ProcessBinary;
+//! use cega::registry::{self, Registration};
+//! use cega::{Grid, ImageType, RawGrid};
+//!
+//! //one pixel per byte
+//! struct Chunky;
+//! impl ProcessBinary for Chunky {
+//!     fn image_type(&self) -> ImageType {
+//!         ImageType::EGA
+//!     }
+//!     fn process_input(&self, buffer: &[u8], width: usize) -> RawGrid {
+//!         Grid::from_flat(buffer.iter().map(|b| b & 0x0F).collect(), width)
+//!     }
+//! }
+//!
+//! let parser = registry::register(Registration {
+//!     encoder: Some(|grid| grid.pixels().copied().collect()),
+//!     ..Registration::new("chunky", Chunky)
+//! })
+//! .unwrap();
+//! assert_eq!(cega::parser::ParserType::type_str("chunky"), Ok(parser));
+//! ```
+
+use std::sync::{Arc, RwLock};
+
+use crate::parser::{ParserType, ProcessBinary};
+use crate::RawGrid;
+
+pub type Encoder = fn(&RawGrid) -> Vec<u8>;
+pub type Sniffer = fn(&[u8]) -> bool;
+
+pub struct Registration {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub parser: Box<dyn ProcessBinary + Send + Sync>,
+    /// Without one, images are written back as CGA like the other formats cega can't encode
+    pub encoder: Option<Encoder>,
+    /// Recognizes the format from its first bytes, for detection
+    pub sniff: Option<Sniffer>,
+    /// Headerless, so scored with the built in raw parsers when detecting the format or width
+    pub raw: bool,
+}
+
+impl Registration {
+    pub fn new(name: &'static str, parser: impl ProcessBinary + Send + Sync + 'static) -> Self {
+        Self {
+            name,
+            aliases: &[],
+            parser: Box::new(parser),
+            encoder: None,
+            sniff: None,
+            raw: false,
+        }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> {
+        std::iter::once(self.name).chain(self.aliases.iter().copied())
+    }
+}
+
+static REGISTERED: RwLock<Vec<Arc<Registration>>> = RwLock::new(Vec::new());
+
+/// Adds the parser for the whole program, under a name no other parser uses
+pub fn register(registration: Registration) -> Result<ParserType, String> {
+    //checked under the same lock as the push, so two threads can't both take a name
+    let mut registered = REGISTERED.write().expect("registry lock");
+    let taken: Vec<&str> = ParserType::BUILT_IN
+        .iter()
+        .flat_map(|(_, names)| names.iter().copied())
+        .chain(registered.iter().flat_map(|r| r.names()))
+        .collect();
+    if let Some(name) = registration.names().find(|name| taken.contains(name)) {
+        return Err(format!("image parser name is already taken: {}", name));
+    }
+    registered.push(Arc::new(registration));
+    Ok(ParserType::Registered(registered.len() - 1))
+}
+
+pub(crate) fn get(index: usize) -> Arc<Registration> {
+    REGISTERED.read().expect("registry lock")[index].clone()
+}
+
+/// Built in parsers first, then registered ones in the order they were added
+pub fn parsers() -> Vec<ParserType> {
+    let registered = REGISTERED.read().expect("registry lock").len();
+    ParserType::BUILT_IN
+        .iter()
+        .map(|(parser, _)| *parser)
+        .chain((0..registered).map(ParserType::Registered))
+        .collect()
+}
+
+/// Every name and alias a parser answers to
+pub fn names() -> Vec<&'static str> {
+    parsers().iter().flat_map(|p| p.names()).collect()
+}
+
+/// Parsers for headerless data, which need a width to be guessed or given
+pub fn raw() -> Vec<ParserType> {
+    let registered = REGISTERED.read().expect("registry lock");
+    ParserType::RAW
+        .into_iter()
+        .chain(
+            (0..registered.len())
+                .filter_map(|i| registered[i].raw.then_some(ParserType::Registered(i))),
+        )
+        .collect()
+}
+
+/// The first registered parser that recognizes the bytes
+pub fn sniff(bytes: &[u8]) -> Option<ParserType> {
+    let registered = REGISTERED.read().expect("registry lock");
+    (0..registered.len())
+        .find(|&i| registered[i].sniff.is_some_and(|sniff| sniff(bytes)))
+        .map(ParserType::Registered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_data::Raw;
+    use crate::parser::CGA;
+    use crate::{Grid, ImageType};
+
+    struct Inverted;
+
+    impl ProcessBinary for Inverted {
+        fn image_type(&self) -> ImageType {
+            ImageType::CGA
+        }
+
+        fn process_input(&self, buffer: &[u8], width: usize) -> RawGrid {
+            let inverted: Vec<u8> = buffer.iter().map(|b| !b).collect();
+            CGA.process_input(&inverted, width)
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let parser = register(Registration {
+            aliases: &["inv"],
+            encoder: Some(|grid| ParserType::CGA.to_bytes(grid).iter().map(|b| !b).collect()),
+            sniff: Some(|bytes| bytes.starts_with(b"INV!")),
+            ..Registration::new("inverted", Inverted)
+        })
+        .unwrap();
+        assert_eq!(ParserType::type_str("inv"), Ok(parser));
+        assert_eq!(parser.name(), "inverted");
+        assert!(parsers().contains(&parser));
+        assert!(names().contains(&"inv"));
+        assert!(!raw().contains(&parser));
+        assert_eq!(sniff(b"INV!...."), Some(parser));
+
+        let image = Raw::new(&[0xFF, 0x1B]).parse(parser, 8).unwrap();
+        assert_eq!(image.data().row(0), &[0, 0, 0, 0, 3, 2, 1, 0]);
+        let grid = Grid::from_flat(vec![3, 2, 1, 0], 4);
+        assert_eq!(parser.to_bytes(&grid), vec![0x1B]);
+
+        assert!(register(Registration::new("cga", Inverted)).is_err());
+        assert!(register(Registration::new("inv", Inverted)).is_err());
+    }
+
+    #[test]
+    fn built_in() {
+        for parser in parsers() {
+            assert_eq!(ParserType::type_str(parser.name()), Ok(parser));
+        }
+        assert!(names().contains(&"erp"));
+        assert!(raw().starts_with(&ParserType::RAW));
+    }
+}
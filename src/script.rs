@@ -0,0 +1,243 @@
+//! Decoders written in [Rhai](https://rhai.rs), for formats too odd for a format description.
+//! A script gets the input as the blob `bytes` and the requested `width`, and returns pixels
+//! as an array of rows or a flat array (or blob) `width` wide, or a map of `pixels`, an optional
+//! `width` and an optional `palette` of EGA color numbers (0-15)
+//!
+//! ```rhai
+//! //one pixel per byte, using only the low nibble
+//! let pixels = [];
+//! for i in 0..bytes.len() { pixels.push(bytes[i] & 0x0F); }
+//! #{ pixels: pixels, palette: [0, 3, 5, 15] }
+//! ```
+//!
+//! [register] makes a script the one `ParserType::Script` (`-i script`) runs
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use rhai::{Array, Blob, Dynamic, Engine, Map, Scope, AST};
+
+use crate::color::palette;
+use crate::{ColorPalette, Grid, RawGrid};
+
+pub const EXTENSION: &str = "rhai";
+//enough for a few full screens a pixel at a time, but not an endless loop
+const MAX_OPERATIONS: u64 = 50_000_000;
+
+thread_local! {
+    static REGISTERED: RefCell<Option<Rc<Script>>> = const { RefCell::new(None) };
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn new(source: &str) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        Ok(Self { engine, ast })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        Self::new(&fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?)
+    }
+
+    /// The pixels, and the palette if the script gave one
+    pub fn run(
+        &self,
+        bytes: &[u8],
+        width: usize,
+    ) -> Result<(RawGrid, Option<ColorPalette>), String> {
+        let mut scope = Scope::new();
+        scope.push("bytes", Blob::from(bytes));
+        scope.push("width", width as rhai::INT);
+        let result: Dynamic = self
+            .engine
+            .eval_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| e.to_string())?;
+
+        let (pixels, width, mut palette) = if result.is_map() {
+            let mut map = result.cast::<Map>();
+            let pixels = map.remove("pixels").ok_or("script returned no pixels")?;
+            let width = match map.remove("width") {
+                Some(w) => w.as_int().map_err(|_| "width must be a number")? as usize,
+                None => width,
+            };
+            let palette = map.remove("palette").map(to_palette).transpose()?;
+            (pixels, width, palette)
+        } else {
+            (result, width, None)
+        };
+        let grid = to_grid(pixels, width)?;
+        if let Some(palette) = &mut palette {
+            if grid.pixels().any(|&p| p as usize >= palette.len()) {
+                return Err(format!(
+                    "pixels must be within the {} color palette",
+                    palette.len()
+                ));
+            }
+            //padded to a full CGA or EGA palette, as the rest of cega expects, with unused black
+            let len = if palette.len() <= 4 { 4 } else { 16 };
+            palette.resize(len, palette::EGA0[0].clone());
+        }
+        Ok((grid, palette))
+    }
+}
+
+/// Make the script the one [crate::parser::ParserType::Script] runs, on this thread
+pub fn register(script: Script) {
+    REGISTERED.with(|registered| *registered.borrow_mut() = Some(Rc::new(script)));
+}
+
+pub fn registered() -> Result<Rc<Script>, String> {
+    REGISTERED
+        .with(|registered| registered.borrow().clone())
+        .ok_or("no script is registered, load one with --script".to_owned())
+}
+
+fn to_pixel(value: &Dynamic) -> Result<u8, String> {
+    value
+        .as_int()
+        .ok()
+        .and_then(|v| u8::try_from(v).ok())
+        .filter(|&v| v < 16)
+        .ok_or(format!(
+            "pixels must be numbers from 0 to 15, not {}",
+            value
+        ))
+}
+
+fn to_grid(pixels: Dynamic, width: usize) -> Result<RawGrid, String> {
+    if pixels.is_blob() {
+        let blob = pixels.cast::<Blob>();
+        if let Some(pixel) = blob.iter().find(|&&p| p >= 16) {
+            return Err(format!(
+                "pixels must be numbers from 0 to 15, not {}",
+                pixel
+            ));
+        }
+        return Ok(Grid::from_flat(blob, width.max(1)));
+    }
+    let array = pixels
+        .try_cast::<Array>()
+        .ok_or("pixels must be an array or blob")?;
+    if array.first().is_some_and(|row| row.is_array()) {
+        let rows = array
+            .into_iter()
+            .map(|row| {
+                row.try_cast::<Array>()
+                    .ok_or("every row must be an array".to_owned())?
+                    .iter()
+                    .map(to_pixel)
+                    .collect::<Result<Vec<u8>, String>>()
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let row_width = rows.first().map_or(0, Vec::len);
+        if rows.iter().any(|row| row.len() != row_width) {
+            return Err("rows must all be the same width".to_owned());
+        }
+        return Ok(Grid::from_flat(rows.concat(), row_width.max(1)));
+    }
+    let flat = array
+        .iter()
+        .map(to_pixel)
+        .collect::<Result<Vec<u8>, String>>()?;
+    Ok(Grid::from_flat(flat, width.max(1)))
+}
+
+fn to_palette(palette: Dynamic) -> Result<ColorPalette, String> {
+    let colors = palette
+        .try_cast::<Array>()
+        .ok_or("palette must be an array of EGA color numbers")?;
+    if colors.len() > 16 {
+        return Err("palette can have at most 16 colors".to_owned());
+    }
+    colors
+        .iter()
+        .map(|c| to_pixel(c).map(|i| palette::EGA0[i as usize].clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_data::Raw;
+    use crate::parser::ParserType;
+
+    #[test]
+    fn runs() {
+        let script = Script::new(
+            "let pixels = []; for i in 0..bytes.len() { pixels.push(bytes[i] & 0x0F); } #{ pixels: pixels, palette: [0, 11, 13, 15] }",
+        )
+        .unwrap();
+        let (grid, palette) = script.run(&[0x10, 0x21, 0x32, 0x43], 2).unwrap();
+        assert_eq!(grid.to_vecs(), vec![vec![0, 1], vec![2, 3]]);
+        assert_eq!(palette, Some(palette::CGA1I.to_vec()));
+
+        let rows = Script::new("[[1, 2, 3], [4, 5, 6]]").unwrap();
+        assert_eq!(rows.run(&[], 8).unwrap().0.width(), 3);
+        let blob = Script::new("bytes").unwrap();
+        assert_eq!(blob.run(&[1, 2, 3, 4], 4).unwrap().0.height(), 1);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Script::new("let x = ").is_err());
+        assert!(Script::new("[16]").unwrap().run(&[], 1).is_err());
+        assert!(Script::new("[[1], [1, 2]]").unwrap().run(&[], 1).is_err());
+        assert!(Script::new("#{ pixels: [2], palette: [0, 1] }")
+            .unwrap()
+            .run(&[], 1)
+            .is_err());
+        assert!(Script::new("loop {}").unwrap().run(&[], 1).is_err());
+        assert!(Script::new(
+            "#{ pixels: [0], palette: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }"
+        )
+        .unwrap()
+        .run(&[], 1)
+        .is_err());
+    }
+
+    #[test]
+    fn parser() {
+        register(Script::new("#{ pixels: bytes, width: 2, palette: [0, 1] }").unwrap());
+        let image = Raw::new(&[1, 0, 0, 1])
+            .parse(ParserType::Script, 320)
+            .unwrap();
+        assert_eq!(image.data().to_vecs(), vec![vec![1, 0], vec![0, 1]]);
+        assert_eq!(image.palette().len(), 4);
+        #[cfg(feature = "terminal")]
+        {
+            use crate::terminal::{default_char_palette, TerminalMode, TerminalPalette};
+            let chars = default_char_palette(image.image_type());
+            let preview = TerminalPalette::new(TerminalMode::ColoredAscii, chars, image.palette());
+            assert_eq!(preview.apply(image.data()).height(), 2);
+        }
+
+        //the palette comes from the same run as the pixels
+        register(
+            Script::new(
+                "#{ pixels: bytes, palette: if width == 1 { [0, 1] } else { [0, 1, 2, 3] } }",
+            )
+            .unwrap(),
+        );
+        let image = Raw::new(&[1, 3]).parse(ParserType::Script, 2).unwrap();
+        assert_eq!(image.palette().len(), 4);
+
+        register(Script::new("loop {}").unwrap());
+        assert!(Raw::new(&[1]).parse(ParserType::Script, 1).is_err());
+        register(Script::new("#{ width: 2 }").unwrap());
+        assert!(Raw::new(&[1]).parse(ParserType::Script, 1).is_err());
+    }
+
+    #[test]
+    fn unregistered() {
+        //each test runs on its own thread, so none is registered here
+        assert!(Raw::new(&[1]).parse(ParserType::Script, 1).is_err());
+    }
+}
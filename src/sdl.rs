@@ -2,7 +2,7 @@ use sdl2::gfx::primitives::DrawRenderer;
 use sdl2::pixels::PixelFormatEnum::RGB888 as ColorFormat;
 use sdl2::{event::Event, keyboard::Keycode};
 
-use crate::{ColorPalette, Palette};
+use crate::{ColorPalette, Palette, RawGrid};
 
 type Sdl2Color = sdl2::pixels::Color;
 type MyColor = crate::color::Color;
@@ -18,8 +18,8 @@ impl From<&MyColor> for Sdl2Color {
 }
 
 pub fn render_sdl(
-    image_data: Vec<Vec<u8>>,
-    palette: ColorPalette,
+    image_data: &RawGrid,
+    palette: &ColorPalette,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
@@ -39,7 +39,7 @@ pub fn render_sdl(
 
     let sdlpal: Palette<Sdl2Color> = palette.iter().map(|m| m.into()).collect();
 
-    for (y, row) in image_data.iter().enumerate() {
+    for (y, row) in image_data.rows().enumerate() {
         for (x, index) in row.iter().enumerate() {
             canvas.pixel(
                 x.try_into().unwrap(),
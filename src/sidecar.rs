@@ -0,0 +1,189 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::color::palette::palette_from_abbr;
+use crate::compression::Compression;
+use crate::file_data::Raw;
+use crate::image::{Image, TileOptions};
+use crate::parser::ParserType;
+
+/// Everything needed to reproduce a decode of a file, saved next to it as `<file>.cega.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecodeParams {
+    pub parser: String,
+    pub width: usize,
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tile_height: Option<usize>,
+    /// How the tiles are laid out, when `tile_height` is set
+    #[serde(default, skip_serializing_if = "TileOptions::is_default")]
+    pub layout: TileOptions,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palette: Option<String>,
+}
+
+impl DecodeParams {
+    pub const EXTENSION: &'static str = "cega.json";
+
+    /// None if the image doesn't know which parser produced it
+    pub fn from_image(image: &Image) -> Option<Self> {
+        Some(Self {
+            parser: image.parser?.name().to_owned(),
+            width: image.width,
+            offset: image.offset,
+            length: image.length,
+            compression: image.compression.map(|c| c.to_string()),
+            tile_height: image.layout.map(|l| l.tile_height),
+            layout: image.layout.map(|l| l.options).unwrap_or_default(),
+            palette: image
+                .palette
+                .as_ref()
+                .and_then(crate::color::palette::abbr)
+                .map(str::to_owned),
+        })
+    }
+
+    pub fn parser(&self) -> Result<ParserType, String> {
+        ParserType::type_str(&self.parser)
+    }
+
+    pub fn compression(&self) -> Result<Option<Compression>, String> {
+        self.compression
+            .as_deref()
+            .map(Compression::type_str)
+            .transpose()
+    }
+
+    /// The bytes the parser will see
+    pub fn data(&self, raw: &Raw) -> Result<Raw, String> {
+        let data = raw.slice(self.offset, self.length);
+        Ok(match self.compression()? {
+            Some(compression) => Raw::new(&compression.decompress(data.bytes())?),
+            None => data,
+        })
+    }
+
+    pub fn decode(&self, raw: &Raw) -> Result<Image, String> {
+        let (parser, width) = (self.parser()?, self.width);
+        let mut image = match self.compression()? {
+            Some(c) => raw.parse_compressed(parser, width, self.offset, self.length, c)?,
+            None => raw.parse_range(parser, width, self.offset, self.length)?,
+        };
+        if let Some(palette) = &self.palette {
+            image.palette = Some(palette_from_abbr(palette));
+        }
+        let Some(tile_height) = self.tile_height else {
+            return Ok(image);
+        };
+        if tile_height == 0 {
+            return Err("tile height can't be 0".to_owned());
+        }
+        let colors = image.palette().len();
+        for color in [self.layout.gutter_color, self.layout.padding_color] {
+            if color as usize >= colors {
+                return Err(format!(
+                    "color {} isn't in the {} color palette",
+                    color, colors
+                ));
+            }
+        }
+        Ok(image.tile_with(tile_height, self.layout))
+    }
+
+    pub fn path_for(image_path: &Path) -> PathBuf {
+        let mut name = image_path.as_os_str().to_owned();
+        name.push(".");
+        name.push(Self::EXTENSION);
+        PathBuf::from(name)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let params: Self = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if params.tile_height == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "tile_height can't be 0",
+            ));
+        }
+        Ok(params)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let raw = Raw::new(&0xFF_FF_FF_FF_FD_7F_F6_9F_F6_9F_FD_7F_FF_FF_FF_FFu128.to_be_bytes());
+        let params = DecodeParams {
+            parser: "cga".to_owned(),
+            width: 8,
+            offset: 4,
+            length: None,
+            compression: None,
+            tile_height: Some(2),
+            layout: TileOptions::default(),
+            palette: Some("cga0".to_owned()),
+        };
+        let image = params.decode(&raw).unwrap();
+        assert_eq!(image.data().width(), 24);
+        assert_eq!(DecodeParams::from_image(&image), Some(params.clone()));
+
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(
+            json,
+            r#"{"parser":"cga","width":8,"offset":4,"tile_height":2,"palette":"cga0"}"#
+        );
+        let minimal: DecodeParams = serde_json::from_str(r#"{"parser":"erp","width":16}"#).unwrap();
+        assert_eq!(minimal.parser(), Ok(ParserType::EGARowPlanar));
+        assert_eq!((minimal.offset, minimal.tile_height), (0, None));
+
+        let gutters = DecodeParams {
+            layout: TileOptions {
+                gutter: 1,
+                gutter_color: 3,
+                ..TileOptions::default()
+            },
+            ..params.clone()
+        };
+        let json = serde_json::to_string(&gutters).unwrap();
+        assert!(json.contains(
+            r#""layout":{"order":"row_major","gutter":1,"gutter_color":3,"padding_color":0}"#
+        ));
+        assert_eq!(gutters.decode(&raw).unwrap().data().width(), 26);
+        let outside = DecodeParams {
+            layout: TileOptions {
+                padding_color: 4,
+                ..TileOptions::default()
+            },
+            ..params.clone()
+        };
+        assert!(outside.decode(&raw).is_err());
+        let flat = DecodeParams {
+            tile_height: Some(0),
+            ..params
+        };
+        assert!(flat.decode(&raw).is_err());
+    }
+
+    #[test]
+    fn sidecar_path() {
+        assert_eq!(
+            DecodeParams::path_for(Path::new("assets/TILES.BIN")),
+            PathBuf::from("assets/TILES.BIN.cega.json")
+        );
+    }
+}
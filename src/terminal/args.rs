@@ -1,13 +1,31 @@
+use crate::compression::Compression;
+use crate::image::{Image, TileOptions, TileOrder};
+use crate::parser::ParserType;
+use crate::preset::{self, Preset};
+use crate::registry;
+use crate::sidecar::DecodeParams;
 use crate::terminal::TerminalMode;
+use crate::tilemap::IndexSize;
+use crate::ImageType;
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::builder::PossibleValuesParser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
-#[clap(version = "0.1", author = "Kenzi Connor")]
+#[clap(
+    version = "0.1",
+    author = "Kenzi Connor",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Args {
-    #[clap(name = "IMAGE")]
-    pub image: PathBuf,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    //always present without a subcommand
+    #[clap(name = "IMAGE", required = true)]
+    pub image: Option<PathBuf>,
 
     #[clap(value_enum, short, long, default_missing_value="a", num_args(0..=1), value_parser = TerminalMode::from_short, 
         help="images will horizontally crop to terminal\na = plain ascii: default for empty -a \nc = colored ascii\np = full pixels via ansi bg color\nh = horizontal half pixels (UGLY)")]
@@ -16,7 +34,8 @@ pub struct Args {
     #[clap(value_parser(["cga0", "cga0i", "cga1", "cga1i", "ega"]),num_args(0..=1), short, long, help="ega palette can be used for cga, but not the inverse\n")]
     pub palette: Option<String>,
 
-    #[clap(short, long, value_parser(["ega_row_planar", "erp", "cga", "png"]), default_value="cga")]
+    #[clap(short, long, value_parser = PossibleValuesParser::new(image_parsers()), default_value="cga",
+        help="auto detects the format from magic numbers or by scoring the raw parsers\nbsave reads the screen mode from the header\nscript runs the --script decoder (scripting feature)")]
     pub image_parser: String,
 
     #[clap(short, long, value_parser = parse_asci_param, help="4 or 16 chars palette like -a \" +%0\"")]
@@ -25,20 +44,548 @@ pub struct Args {
     #[clap(short, long, default_value_t = 320)]
     pub width: usize,
 
-    #[clap(short, long, help = "format based on extension - see image crate")]
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "pick the width whose rows line up best"
+    )]
+    pub auto_width: bool,
+
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "bytes to skip before decoding, e.g. a header"
+    )]
+    pub offset: usize,
+
+    #[clap(long, help = "bytes to decode after the offset [default: the rest]")]
+    pub length: Option<usize>,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "pick the offset (up to 32 bytes) that decodes most coherently"
+    )]
+    pub auto_offset: bool,
+
+    #[clap(
+        long,
+        value_name = "COMPRESSION",
+        value_parser = parse_compression,
+        help = "decompress the data before parsing, and compress -g output\nrle[:marker], highbit, rlew[:tag] or lzw (Ultima V), in hex [rle default: 02 (Ultima IV), rlew: abcd (id)]"
+    )]
+    pub compression: Option<String>,
+
+    #[clap(
+        short,
+        long,
+        help = "format based on extension - see image crate, plus pcx and lbm"
+    )]
     pub output_file: Option<PathBuf>,
 
-    #[clap(short, long)]
+    #[clap(
+        short,
+        long,
+        help = "raw output in the parser's format, or a BSAVE file for .bsv/.pic/.bsave"
+    )]
     pub ga_file: Option<PathBuf>,
 
-    #[clap(short, long)]
+    #[clap(short, long, value_parser = parse_tile_size)]
     pub tile_height: Option<usize>,
 
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "detect the tile size, and tile the image by it if it is a strip one tile wide\n(--auto-width can find that width). Tiles already side by side at -w are left as they are"
+    )]
+    pub auto_tile: bool,
+
+    #[clap(
+        long,
+        help = "tiles per row when tiling [default: as many as fit in 320 pixels]"
+    )]
+    pub columns: Option<usize>,
+
+    #[clap(long, default_value = "row", value_parser = parse_tile_order, help = "lay tiles out across rows (row) or down columns (column)")]
+    pub order: String,
+
+    #[clap(long, default_value_t = 0, help = "pixels between tiles")]
+    pub gutter: usize,
+
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "palette index to fill the gutter with"
+    )]
+    pub gutter_color: u8,
+
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "palette index to fill the empty cells of an incomplete last row with"
+    )]
+    pub padding_color: u8,
+
     #[clap(short, long, default_value_t = false)]
     pub sdl: bool,
 
     #[clap(short, long, default_value_t = false)]
     pub quiet: bool,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "unpack an LZEXE packed executable and use its load image"
+    )]
+    pub unpack: bool,
+
+    #[cfg(feature = "scripting")]
+    #[clap(
+        long,
+        value_name = "RHAI",
+        help = "the Rhai decoder -i script runs (see src/script.rs)"
+    )]
+    pub script: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "TOML",
+        conflicts_with_all = ["preset", "sidecar", "compression", "auto_width", "auto_offset", "auto_tile"],
+        help = "decode with a format description file instead of -i, -w and -t (see formats/), after --offset and within --length"
+    )]
+    pub format: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_parser = parse_preset,
+        help = "decode with a known game file's layout instead of the flags above\nauto looks the file up by name and size, or name one: ultima4_tiles, ultima4_charset, ultima4_screen, ultima5_tiles"
+    )]
+    pub preset: Option<String>,
+
+    #[clap(long, value_name = "SIDECAR", num_args(0..=1),
+        help = "decode using a saved sidecar instead of the flags above\ndefaults to <IMAGE>.cega.json")]
+    pub sidecar: Option<Option<PathBuf>>,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "save the decode settings to <IMAGE>.cega.json"
+    )]
+    pub save_sidecar: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[clap(about = "find likely graphics inside executables and archives")]
+    Scan(ScanArgs),
+    #[clap(about = "export the pictures, sprites and tiles of an id Software EGAGRAPH archive")]
+    Egagraph(EgagraphArgs),
+    #[clap(about = "draw a map file of tile indices with a tileset")]
+    Tilemap(TilemapArgs),
+    #[clap(about = "cut an image into distinct tiles and the map that rebuilds it")]
+    Dedupe(DedupeArgs),
+    #[clap(about = "find where a tileset's tiles appear in an image")]
+    Locate(LocateArgs),
+    #[cfg(feature = "tiled")]
+    #[clap(about = "write a map edited in Tiled back out in its raw format")]
+    Tmx(TmxArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ScanArgs {
+    #[clap(name = "FILE")]
+    pub file: PathBuf,
+
+    #[clap(long, default_value_t = crate::detect::scan::WINDOW, help = "bytes scored at a time")]
+    pub window: usize,
+
+    #[clap(long, help = "bytes between windows [default: half the window]")]
+    pub step: Option<usize>,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "unpack an LZEXE packed executable and scan its load image"
+    )]
+    pub unpack: bool,
+
+    #[clap(
+        short,
+        long,
+        help = "directory to save each region to as <FILE>_<offset>.png"
+    )]
+    pub output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct EgagraphArgs {
+    #[clap(name = "EGAGRAPH")]
+    pub graph: PathBuf,
+
+    #[clap(name = "EGAHEAD")]
+    pub head: PathBuf,
+
+    #[clap(name = "EGADICT")]
+    pub dict: PathBuf,
+
+    #[clap(long, default_value = "keen4", value_parser(["keen4"]), help = "game the chunk layout is from")]
+    pub game: String,
+
+    #[clap(
+        long,
+        value_name = "TOML",
+        conflicts_with = "game",
+        help = "the chunk layout of another game (see egagraph::Layout::parse)"
+    )]
+    pub layout: Option<PathBuf>,
+
+    #[clap(short, long, help = "directory to save each image to as <name>.png")]
+    pub output_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TilemapArgs {
+    #[clap(name = "TILESET")]
+    pub tileset: PathBuf,
+
+    #[clap(name = "MAP")]
+    pub map: PathBuf,
+
+    #[clap(long, help = "tiles per map row")]
+    pub map_width: usize,
+
+    #[clap(long, default_value = "u8", value_parser = parse_index_size, help = "bytes per tile index: u8 or u16 (little endian)")]
+    pub index: String,
+
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "bytes to skip before the map, e.g. a header"
+    )]
+    pub map_offset: usize,
+
+    #[clap(short, long, value_parser = PossibleValuesParser::new(registry::names()), default_value = "cga", help = "the tileset's parser")]
+    pub image_parser: String,
+
+    #[clap(long, value_parser = parse_preset, conflicts_with = "image_parser", help = "decode the tileset with a known layout instead, e.g. ultima4_tiles")]
+    pub preset: Option<String>,
+
+    #[clap(value_parser(["cga0", "cga0i", "cga1", "cga1i", "ega"]), short, long)]
+    pub palette: Option<String>,
+
+    #[clap(
+        long,
+        default_value_t = 16,
+        value_parser = parse_tile_size,
+        help = "ignored with a preset, which knows its tile size"
+    )]
+    pub tile_width: usize,
+
+    #[clap(long, value_parser = parse_tile_size, help = "[default: the tile width]")]
+    pub tile_height: Option<usize>,
+
+    #[clap(short, long, help = "format based on extension - see image crate")]
+    pub output_file: Option<PathBuf>,
+
+    #[clap(value_enum, short, long, default_missing_value = "a", num_args(0..=1), value_parser = TerminalMode::from_short, help = "see the main -a")]
+    pub ascii_preview: Option<TerminalMode>,
+
+    #[clap(short, long, default_value_t = false)]
+    pub sdl: bool,
+
+    #[cfg(feature = "tiled")]
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "export the tileset (a PNG atlas and .tsx) and the map (.tmx) for Tiled"
+    )]
+    pub tiled: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DedupeArgs {
+    #[clap(name = "IMAGE")]
+    pub image: PathBuf,
+
+    #[clap(short, long, value_parser = PossibleValuesParser::new(registry::names()), default_value = "cga", help = "the image's (and any --tileset's) parser, png for screenshots")]
+    pub image_parser: String,
+
+    #[clap(short, long, default_value_t = 320)]
+    pub width: usize,
+
+    #[clap(value_parser(["cga0", "cga0i", "cga1", "cga1i", "ega"]), short, long)]
+    pub palette: Option<String>,
+
+    #[clap(long, default_value_t = 8, value_parser = parse_tile_size)]
+    pub tile_width: usize,
+
+    #[clap(long, value_parser = parse_tile_size, help = "[default: the tile width]")]
+    pub tile_height: Option<usize>,
+
+    #[cfg(feature = "tiled")]
+    #[clap(
+        long,
+        default_value_t = false,
+        requires = "tiled",
+        help = "draw mirror images of a tile flipped instead of keeping them, needs --tiled as only the .tmx keeps flips"
+    )]
+    pub flips: bool,
+
+    #[clap(
+        long,
+        help = "a known tileset, decoded at the tile width, whose tiles keep their numbers"
+    )]
+    pub tileset: Option<PathBuf>,
+
+    #[clap(long, value_parser = PossibleValuesParser::new(registry::names()), help = "the --tileset's parser [default: -i, or cga for png, which must then only use CGA colors]")]
+    pub tileset_parser: Option<String>,
+
+    #[clap(
+        short,
+        long,
+        help = "directory to save <IMAGE>_tiles.png, <IMAGE>_tiles.bin (in the --tileset-parser format) and <IMAGE>_map.bin to"
+    )]
+    pub output_dir: Option<PathBuf>,
+
+    #[cfg(feature = "tiled")]
+    #[clap(
+        long,
+        default_value_t = false,
+        requires = "output_dir",
+        help = "also save <IMAGE>_tiles.tsx and <IMAGE>.tmx for Tiled, which keeps flips"
+    )]
+    pub tiled: bool,
+}
+
+impl DedupeArgs {
+    pub fn decode_params(&self) -> Result<DecodeParams, String> {
+        let parser = ParserType::type_str(&self.image_parser)?;
+        Ok(DecodeParams {
+            parser: parser.name().to_owned(),
+            width: self.width,
+            offset: 0,
+            length: None,
+            compression: None,
+            tile_height: None,
+            layout: TileOptions::default(),
+            palette: palette_for(parser, &self.palette),
+        })
+    }
+
+    /// The parser for a tileset of `image`'s tiles
+    pub fn tileset_parser(&self, image: &Image) -> Result<ParserType, String> {
+        tileset_parser(&self.image_parser, self.tileset_parser.as_deref(), image)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LocateArgs {
+    #[clap(name = "TILESET")]
+    pub tileset: PathBuf,
+
+    #[clap(name = "IMAGE")]
+    pub image: PathBuf,
+
+    #[clap(short, long, value_parser = PossibleValuesParser::new(registry::names()), default_value = "cga", help = "the image's parser, png for screenshots")]
+    pub image_parser: String,
+
+    #[clap(short, long, default_value_t = 320)]
+    pub width: usize,
+
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "bytes to skip before decoding the image"
+    )]
+    pub offset: usize,
+
+    #[clap(value_parser(["cga0", "cga0i", "cga1", "cga1i", "ega"]), short, long)]
+    pub palette: Option<String>,
+
+    #[clap(long, value_parser = PossibleValuesParser::new(registry::names()), help = "the tileset's parser [default: -i, or cga for png, which must then only use CGA colors]")]
+    pub tileset_parser: Option<String>,
+
+    #[clap(long, default_value_t = 16, value_parser = parse_tile_size)]
+    pub tile_width: usize,
+
+    #[clap(long, value_parser = parse_tile_size, help = "[default: the tile width]")]
+    pub tile_height: Option<usize>,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "also find mirror images of the tiles"
+    )]
+    pub flips: bool,
+
+    #[clap(
+        short,
+        long,
+        help = "save the image as a png with the tiles found outlined"
+    )]
+    pub output_file: Option<PathBuf>,
+}
+
+impl LocateArgs {
+    pub fn decode_params(&self) -> Result<DecodeParams, String> {
+        let parser = ParserType::type_str(&self.image_parser)?;
+        Ok(DecodeParams {
+            parser: parser.name().to_owned(),
+            width: self.width,
+            offset: self.offset,
+            length: None,
+            compression: None,
+            tile_height: None,
+            layout: TileOptions::default(),
+            palette: palette_for(parser, &self.palette),
+        })
+    }
+
+    /// The parser for a tileset of `image`'s tiles
+    pub fn tileset_parser(&self, image: &Image) -> Result<ParserType, String> {
+        tileset_parser(&self.image_parser, self.tileset_parser.as_deref(), image)
+    }
+}
+
+#[cfg(feature = "tiled")]
+#[derive(clap::Args, Debug)]
+pub struct TmxArgs {
+    #[clap(name = "TMX")]
+    pub tmx: PathBuf,
+
+    #[clap(short, long, help = "the raw map file to write")]
+    pub output_file: PathBuf,
+
+    #[clap(long, default_value = "u8", value_parser = parse_index_size, help = "bytes per tile index: u8 or u16 (little endian)")]
+    pub index: String,
+
+    #[clap(
+        long,
+        value_name = "MAP",
+        requires = "map_offset",
+        help = "the map file exported from, to copy its header from"
+    )]
+    pub original: Option<PathBuf>,
+
+    #[clap(
+        long,
+        requires = "original",
+        help = "bytes of header to copy from the original"
+    )]
+    pub map_offset: Option<usize>,
+}
+
+impl TilemapArgs {
+    /// How to decode the tileset. Its tile height stays set (a preset may know it) but the
+    /// tileset is cut up from the strip as decoded, not tiled
+    pub fn decode_params(&self, bytes: &[u8]) -> Result<DecodeParams, String> {
+        match self.preset.as_deref() {
+            Some(preset::AUTO) => Preset::lookup(&self.tileset, bytes)
+                .map(|preset| preset.params)
+                .ok_or(format!("no preset knows {}", self.tileset.display())),
+            Some(name) => Preset::find(name).map(|preset| preset.params),
+            None => {
+                let parser = ParserType::type_str(&self.image_parser)?;
+                Ok(DecodeParams {
+                    parser: parser.name().to_owned(),
+                    width: self.tile_width,
+                    offset: 0,
+                    length: None,
+                    compression: None,
+                    tile_height: self.tile_height,
+                    layout: TileOptions::default(),
+                    palette: palette_for(parser, &self.palette),
+                })
+            }
+        }
+    }
+}
+
+impl Args {
+    pub fn decode_params(&self, parser: ParserType) -> DecodeParams {
+        DecodeParams {
+            parser: parser.name().to_owned(),
+            width: self.width,
+            offset: self.offset,
+            length: self.length,
+            compression: self.compression.clone(),
+            tile_height: self.tile_height,
+            layout: TileOptions {
+                columns: self.columns,
+                order: TileOrder::type_str(&self.order).unwrap_or_default(),
+                gutter: self.gutter,
+                gutter_color: self.gutter_color,
+                padding_color: self.padding_color,
+            },
+            palette: palette_for(parser, &self.palette),
+        }
+    }
+}
+
+/// The palette -p asks for, or the default for the parser's image type
+fn palette_for(parser: ParserType, palette: &Option<String>) -> Option<String> {
+    if parser.embeds_palette() {
+        //only override the file's palette if asked to
+        palette.clone()
+    } else if let ImageType::EGA = parser.image_type() {
+        Some("ega".to_owned())
+    } else {
+        Some(palette.clone().unwrap_or("cga1".to_owned()))
+    }
+}
+
+/// Screenshots can't be written back as png, so their tiles are CGA
+fn tileset_parser(
+    image_parser: &str,
+    tileset_parser: Option<&str>,
+    image: &Image,
+) -> Result<ParserType, String> {
+    let parser = match tileset_parser {
+        Some(name) => ParserType::type_str(name)?,
+        //pngs are read in the CGA colors, anything else is out of range and caught below
+        None if image_parser == "png" => ParserType::CGA,
+        None => ParserType::type_str(image_parser)?,
+    };
+    let colors = parser.image_type().palette_length();
+    if image.data().pixels().any(|&p| p as usize >= colors) {
+        return Err(format!(
+            "the image has colors {} can't store, pick another --tileset-parser",
+            parser.name()
+        ));
+    }
+    Ok(parser)
+}
+
+/// Every registered parser, plus the modes that pick one from the file
+fn image_parsers() -> Vec<&'static str> {
+    [registry::names(), vec!["bsave", "auto"]].concat()
+}
+
+fn parse_preset(arg: &str) -> Result<String, String> {
+    if arg == preset::AUTO {
+        return Ok(arg.to_owned());
+    }
+    Preset::find(arg).map(|_| arg.to_owned())
+}
+
+fn parse_tile_size(arg: &str) -> Result<usize, String> {
+    match arg.parse() {
+        Ok(0) => Err("tiles need at least 1 pixel".to_owned()),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("{} isn't a number of pixels", arg)),
+    }
+}
+
+fn parse_index_size(arg: &str) -> Result<String, String> {
+    IndexSize::type_str(arg).map(|_| arg.to_owned())
+}
+
+fn parse_tile_order(arg: &str) -> Result<String, String> {
+    TileOrder::type_str(arg).map(|_| arg.to_owned())
+}
+
+fn parse_compression(arg: &str) -> Result<String, String> {
+    Compression::type_str(arg).map(|_| arg.to_owned())
 }
 
 fn parse_asci_param(arg: &str) -> Result<String, String> {
@@ -1,76 +1,506 @@
 #![cfg(feature = "terminal")]
 
 use std::fs;
-use std::path::Path;
 
 use clap::Parser;
 
 use cega::color::palette::palette_from_abbr;
-use cega::file_data;
-use cega::image;
+use cega::description::Description;
+use cega::image::Image;
 use cega::parser::ParserType;
 #[cfg(feature = "png")]
 use cega::png;
+use cega::preset::{self, Preset};
 #[cfg(feature = "gui")]
 use cega::sdl::render_sdl;
+use cega::sidecar::DecodeParams;
 use cega::terminal::{self, args, *};
-use cega::ImageType;
+#[cfg(feature = "tiled")]
+use cega::tiled;
+use cega::tilemap::{IndexSize, Tilemap, Tileset};
+use cega::{bsave, detect, egagraph, exe, file_data, lbm, pcx};
 
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = args::Args::parse();
+    match args.command {
+        Some(args::Command::Scan(scan_args)) => return scan(scan_args),
+        Some(args::Command::Egagraph(egagraph_args)) => return egagraph(egagraph_args),
+        Some(args::Command::Tilemap(tilemap_args)) => return tilemap(tilemap_args),
+        Some(args::Command::Dedupe(dedupe_args)) => return dedupe(dedupe_args),
+        Some(args::Command::Locate(locate_args)) => return locate(locate_args),
+        #[cfg(feature = "tiled")]
+        Some(args::Command::Tmx(tmx_args)) => return tmx(tmx_args),
+        None => (),
+    }
+    let image_path = args.image.clone().expect("clap requires IMAGE");
 
-    let file_data = file_data::Raw::new(&std::fs::read(Path::new(&args.image))?);
-    let parser = ParserType::type_str(&args.image_parser);
-    let image = file_data.parse(parser, args.width);
-
-    let image_data = if args.tile_height.is_some() {
-        image::tile(image.data(), args.tile_height.unwrap())
-    } else {
-        image.data()
-    };
+    let mut file_data = file_data::Raw::new(&std::fs::read(&image_path)?);
+    if args.unpack {
+        file_data = file_data.unpack()?;
+    }
 
-    //dbg!(image_data.clone());
+    #[cfg(feature = "scripting")]
+    if let Some(path) = &args.script {
+        cega::script::register(cega::script::Script::load(path)?);
+    }
 
-    let palette_string = if let ImageType::EGA = parser.image_type() {
-        "ega".to_owned()
-    } else {
-        args.palette.unwrap_or("cga1".to_owned())
+    let mut params = match &args.sidecar {
+        Some(path) => DecodeParams::load(
+            &path
+                .clone()
+                .unwrap_or_else(|| DecodeParams::path_for(&image_path)),
+        )?,
+        None if args.preset.is_some() => {
+            let preset = match args.preset.as_deref().unwrap_or_default() {
+                preset::AUTO => Preset::lookup(&image_path, file_data.bytes())
+                    .ok_or(format!("no preset knows {}", image_path.display()))?,
+                name => Preset::find(name)?,
+            };
+            if !args.quiet {
+                println!("Preset: {} ({})", preset.name, preset.description);
+            }
+            preset.params
+        }
+        //a BSAVE header is detected with certainty, as the only result
+        None if args.image_parser == "auto" || args.image_parser == "bsave" => {
+            if args.image_parser == "bsave" && bsave::Header::read(file_data.bytes()).is_none() {
+                return Err("not a BSAVE file".into());
+            }
+            let detected = detect::format::detect(&file_data);
+            if !args.quiet && args.image_parser == "auto" {
+                println!("Likely formats: {}", terminal::format_scores(&detected, 5));
+            }
+            let best = detected.first().ok_or("could not detect the format")?;
+            let mut params = DecodeParams {
+                width: best.width,
+                ..args.decode_params(best.parser)
+            };
+            //a recognized header says where the data is
+            if best.offset > 0 {
+                params.offset = best.offset;
+                params.length = best.length;
+            }
+            params
+        }
+        None => args.decode_params(ParserType::type_str(&args.image_parser)?),
     };
+    if args.auto_width {
+        let ranked = detect::width::rank(&params.data(&file_data)?, &[params.parser()?]);
+        if let Some(best) = ranked.first() {
+            params.width = best.width;
+        }
+        if !args.quiet {
+            println!("Likely widths: {}", terminal::format_scores(&ranked, 5));
+        }
+    }
+    if args.auto_offset {
+        if params.compression.is_some() {
+            return Err("--auto-offset can't look for a header inside compressed data".into());
+        }
+        //offsets into what --offset and --length already select
+        let base = params.offset;
+        let ranked: Vec<_> = detect::offset::rank(
+            &params.data(&file_data)?,
+            params.parser()?,
+            params.width,
+            detect::offset::MAX_OFFSET,
+        )
+        .into_iter()
+        .map(|score| detect::offset::OffsetScore {
+            offset: base + score.offset,
+            ..score
+        })
+        .collect();
+        if let Some(best) = ranked.first() {
+            params.length = params.length.map(|l| l.saturating_sub(best.offset - base));
+            params.offset = best.offset;
+        }
+        if !args.quiet {
+            println!("Likely offsets: {}", terminal::format_scores(&ranked, 5));
+        }
+    }
+    if args.auto_tile {
+        let untiled = DecodeParams {
+            tile_height: None,
+            ..params.clone()
+        }
+        .decode(&file_data)?;
+        let ranked = detect::tiles::rank(untiled.data());
+        //tiles already laid out side by side don't need retiling
+        if let Some(best) = ranked.first().filter(|best| best.width == params.width) {
+            params.tile_height = Some(best.height);
+        }
+        if !args.quiet {
+            println!("Likely tile sizes: {}", terminal::format_scores(&ranked, 5));
+            if let Some(best) = ranked.first().filter(|best| best.width != params.width) {
+                println!(
+                    "{}x{} tiles are already side by side at width {}, so aren't retiled",
+                    best.width, best.height, params.width
+                );
+            }
+        }
+    }
+    if args.save_sidecar {
+        params.save(&DecodeParams::path_for(&image_path))?;
+    }
 
-    let palette = palette_from_abbr(&palette_string);
+    let description = args.format.as_deref().map(Description::load).transpose()?;
+    let image = match &description {
+        Some(description) => {
+            let data = file_data.slice(params.offset, params.length);
+            let mut image = description.decode_with(&data, params.layout)?;
+            image.offset += params.offset;
+            if let Some(name) = &args.palette {
+                let palette = palette_from_abbr(name);
+                if image.data().pixels().any(|&p| p as usize >= palette.len()) {
+                    return Err(
+                        format!("{} has too few colors for {}", name, description.name).into(),
+                    );
+                }
+                image.palette = Some(palette);
+            }
+            image
+        }
+        None => params.decode(&file_data)?,
+    };
+    let palette = image.palette();
+    let image_data = image.data();
 
-    #[cfg(feature = "png")]
     if let Some(output) = args.output_file {
-        png::save(output, image_data.clone(), palette.clone())?
+        if pcx::is_pcx_path(&output) {
+            fs::write(output, pcx::encode(image_data, &palette))?
+        } else if lbm::is_lbm_path(&output) {
+            fs::write(
+                output,
+                lbm::encode(image_data, &palette, &image.color_ranges),
+            )?
+        } else {
+            #[cfg(feature = "png")]
+            png::save(output, &image)?
+        }
     }
 
     if let Some(ga_file) = args.ga_file {
-        let bytes = parser.to_bytes(image_data.clone());
+        //written as decoded, not as the laid out sheet
+        let image = image.untiled();
+        let bytes = if bsave::is_bsave_path(&ga_file) {
+            bsave::encode(&image)?
+        } else {
+            match &description {
+                Some(description) => description.encode(image.data())?,
+                None => {
+                    let bytes = params.parser()?.encode(&image);
+                    match image.compression {
+                        Some(compression) => compression.compress(&bytes),
+                        None => bytes,
+                    }
+                }
+            }
+        };
         fs::write(ga_file, bytes).unwrap();
     }
 
     if let Some(ascii_mode) = args.ascii_preview {
-        let ascii = if args.custom_ascii.is_some() {
-            args.custom_ascii.unwrap().chars().collect()
-        } else {
-            default_char_palette(parser.image_type())
-        };
+        print_preview(&image, ascii_mode, args.custom_ascii);
+    }
+
+    if !args.quiet {
+        println!("\n{}", image.suggestions());
+    }
+
+    #[cfg(feature = "gui")]
+    if args.sdl {
+        render_sdl(image_data, &palette)?
+    }
+    Ok(())
+}
 
-        let tp = TerminalPalette::new(ascii_mode, ascii, palette.clone());
-        print!(
-            "{}",
-            terminal::disable_wrapping(terminal::to_string(tp.apply(image_data.clone())))
+fn scan(args: args::ScanArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file_data = file_data::Raw::new(&fs::read(&args.file)?);
+    match exe::detect(file_data.bytes()) {
+        Some(_) if args.unpack => file_data = file_data.unpack()?,
+        Some(packer) => println!("Packed with {}, try --unpack", packer),
+        None => (),
+    }
+    let step = args.step.unwrap_or(args.window / 2);
+    let regions = file_data.scan(args.window, step);
+    if regions.is_empty() {
+        println!("No likely graphics found");
+    }
+    for region in &regions {
+        println!("{}", region);
+    }
+
+    #[cfg(feature = "png")]
+    if let Some(dir) = args.output_dir {
+        fs::create_dir_all(&dir)?;
+        let stem = args
+            .file
+            .file_name()
+            .map_or("region".into(), |name| name.to_string_lossy());
+        for region in &regions {
+            let image = file_data.parse_range(
+                region.best.parser,
+                region.best.width,
+                region.offset,
+                Some(region.length),
+            )?;
+            png::save(
+                dir.join(format!("{}_{:x}.png", stem, region.offset)),
+                &image,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn egagraph(args: args::EgagraphArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let archive = egagraph::Archive::open(
+        &fs::read(&args.graph)?,
+        &fs::read(&args.head)?,
+        &fs::read(&args.dict)?,
+        match &args.layout {
+            Some(path) => egagraph::Layout::load(path)?,
+            None => egagraph::Layout::preset(&args.game)?,
+        },
+    )?;
+    let images = archive.images()?;
+    for (name, image) in &images {
+        println!(
+            "{} {}x{}",
+            name,
+            image.data().width(),
+            image.data().height()
         );
     }
 
-    //downside of the current approach is the image doesn't know it's been retiled, so need to know to not ask it if it's tall
-    if !args.quiet && args.tile_height.is_none() {
-        println!("\n{}", image.suggestions());
+    #[cfg(feature = "png")]
+    if let Some(dir) = args.output_dir {
+        fs::create_dir_all(&dir)?;
+        for (name, image) in &images {
+            png::save(dir.join(format!("{}.png", name)), image)?;
+        }
+    }
+    Ok(())
+}
+
+fn tilemap(args: args::TilemapArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let tileset_data = file_data::Raw::new(&fs::read(&args.tileset)?);
+    let params = args.decode_params(tileset_data.bytes())?;
+    let tile_height = params.tile_height.unwrap_or(params.width);
+    let tileset_image = DecodeParams {
+        tile_height: None,
+        ..params.clone()
     }
+    .decode(&tileset_data)?;
+    let tileset = Tileset::from_grid(tileset_image.data(), params.width, tile_height);
+
+    let map_bytes = fs::read(&args.map)?;
+    let map = Tilemap::read(
+        map_bytes
+            .get(args.map_offset..)
+            .ok_or("map offset is past the end")?,
+        args.map_width,
+        IndexSize::type_str(&args.index)?,
+    )?;
+    let image = Image {
+        palette: Some(tileset_image.palette()),
+        ..Image::new(map.render(&tileset)?)
+    };
+    println!(
+        "{}x{} tiles of {}x{}, from {} tiles",
+        map.width(),
+        map.height(),
+        params.width,
+        tile_height,
+        tileset.len()
+    );
 
+    #[cfg(feature = "png")]
+    if let Some(output) = args.output_file {
+        png::save(output, &image)?
+    }
+    if let Some(ascii_mode) = args.ascii_preview {
+        print_preview(&image, ascii_mode, None);
+    }
     #[cfg(feature = "gui")]
     if args.sdl {
-        render_sdl(image_data, palette)?
+        render_sdl(image.data(), &image.palette())?
+    }
+    #[cfg(feature = "tiled")]
+    if let Some(dir) = args.tiled {
+        fs::create_dir_all(&dir)?;
+        let stem = |path: &std::path::Path| {
+            path.file_stem()
+                .map_or("map".into(), |stem| stem.to_string_lossy().into_owned())
+        };
+        let tsx = tiled::export_tileset(&dir, &stem(&args.tileset), &tileset, &image.palette())?;
+        let tmx = dir.join(format!("{}.{}", stem(&args.map), tiled::TMX_EXTENSION));
+        fs::write(&tmx, tiled::tmx(&map, None, &tileset, &tsx))?;
+        println!("Saved {}", tmx.display());
+    }
+    Ok(())
+}
+
+fn dedupe(args: args::DedupeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let params = args.decode_params()?;
+    let image = params.decode(&file_data::Raw::new(&fs::read(&args.image)?))?;
+    let tile_height = args.tile_height.unwrap_or(args.tile_width);
+    let tileset_parser = args.tileset_parser(&image)?;
+    let known = match &args.tileset {
+        Some(path) => {
+            let tiles = DecodeParams {
+                parser: tileset_parser.name().to_owned(),
+                width: args.tile_width,
+                ..params.clone()
+            }
+            .decode(&file_data::Raw::new(&fs::read(path)?))?;
+            Tileset::from_grid(tiles.data(), args.tile_width, tile_height)
+        }
+        None => Tileset::new(args.tile_width, tile_height),
+    };
+    //the raw map has no room for flips, so they're only drawn for a tmx
+    #[cfg(feature = "tiled")]
+    let flips = args.flips;
+    #[cfg(not(feature = "tiled"))]
+    let flips = false;
+    let deduplicated = known.deduplicate(image.data(), flips)?;
+    let (map, tileset) = (&deduplicated.map, &deduplicated.tileset);
+    let new = tileset.len() - deduplicated.known;
+    println!(
+        "{}x{} tiles of {}x{}, {} distinct",
+        map.width(),
+        map.height(),
+        args.tile_width,
+        tile_height,
+        new
+    );
+    if deduplicated.known > 0 {
+        let matched = map
+            .indices
+            .pixels()
+            .filter(|&&i| (i as usize) < deduplicated.known)
+            .count();
+        println!(
+            "{} of {} cells are from the known tileset, {} new tiles are added after its {}",
+            matched,
+            map.indices.len(),
+            new,
+            deduplicated.known
+        );
+    }
+    if flips {
+        let flipped = deduplicated
+            .flips
+            .pixels()
+            .filter(|f| f.is_flipped())
+            .count();
+        println!("{} cells are drawn flipped", flipped);
+    }
+
+    if let Some(dir) = args.output_dir {
+        fs::create_dir_all(&dir)?;
+        let stem = args
+            .image
+            .file_stem()
+            .map_or("image".into(), |stem| stem.to_string_lossy().into_owned());
+        let tiles_name = format!("{}_tiles", stem);
+        #[cfg(feature = "png")]
+        png::save(
+            dir.join(format!("{}.png", tiles_name)),
+            &Image {
+                palette: Some(image.palette()),
+                ..Image::new(cega::image::tile(&tileset.strip(), tile_height))
+            },
+        )?;
+        fs::write(
+            dir.join(format!("{}.bin", tiles_name)),
+            tileset_parser.to_bytes(&tileset.strip()),
+        )?;
+        fs::write(dir.join(format!("{}_map.bin", stem)), map.to_bytes())?;
+        #[cfg(feature = "tiled")]
+        if args.tiled {
+            let tsx = tiled::export_tileset(&dir, &tiles_name, tileset, &image.palette())?;
+            fs::write(
+                dir.join(format!("{}.{}", stem, tiled::TMX_EXTENSION)),
+                tiled::tmx(map, Some(&deduplicated.flips), tileset, &tsx),
+            )?;
+        }
     }
     Ok(())
 }
+
+fn locate(args: args::LocateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let tile_height = args.tile_height.unwrap_or(args.tile_width);
+    let params = args.decode_params()?;
+    let image = params.decode(&file_data::Raw::new(&fs::read(&args.image)?))?;
+    let tileset = DecodeParams {
+        parser: args.tileset_parser(&image)?.name().to_owned(),
+        width: args.tile_width,
+        offset: 0,
+        ..params.clone()
+    }
+    .decode(&file_data::Raw::new(&fs::read(&args.tileset)?))?
+    .tileset(args.tile_width, tile_height);
+
+    let found = detect::locate::locate(&tileset, image.data(), args.flips);
+    for place in &found {
+        println!("{}", place);
+    }
+    let mut tiles: Vec<usize> = found.iter().map(|place| place.tile).collect();
+    tiles.sort_unstable();
+    tiles.dedup();
+    println!(
+        "{} found, of {} of the {} tiles. {} on the {}x{} grid",
+        found.len(),
+        tiles.len(),
+        tileset.len(),
+        found
+            .iter()
+            .filter(|place| place.is_aligned(&tileset))
+            .count(),
+        args.tile_width,
+        tile_height
+    );
+
+    #[cfg(feature = "png")]
+    if let Some(output) = args.output_file {
+        let boxes: Vec<_> = found
+            .iter()
+            .map(|place| (place.x, place.y, args.tile_width, tile_height))
+            .collect();
+        png::annotate(image.data(), &image.palette(), &boxes).save(output)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "tiled")]
+fn tmx(args: args::TmxArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let map = tiled::read_tmx(
+        &fs::read_to_string(&args.tmx)?,
+        IndexSize::type_str(&args.index)?,
+    )?;
+    println!("{}x{} tiles", map.width(), map.height());
+    let mut bytes = match (&args.original, args.map_offset) {
+        (Some(original), Some(offset)) => fs::read(original)?
+            .get(..offset)
+            .ok_or("map offset is past the end of the original")?
+            .to_vec(),
+        _ => vec![],
+    };
+    bytes.extend(map.to_bytes());
+    fs::write(args.output_file, bytes)?;
+    Ok(())
+}
+
+fn print_preview(image: &Image, mode: TerminalMode, custom_ascii: Option<String>) {
+    let ascii = match custom_ascii {
+        Some(custom_ascii) => custom_ascii.chars().collect(),
+        None => default_char_palette(image.image_type()),
+    };
+    let tp = TerminalPalette::new(mode, ascii, image.palette());
+    print!(
+        "{}",
+        terminal::disable_wrapping(terminal::to_string(&tp.apply(image.data())))
+    );
+}
@@ -1,5 +1,7 @@
 pub mod args;
 
+use std::fmt::Display;
+
 use crate::{ColorPalette, Grid, ImageType, Palette, RawGrid};
 
 pub type CharPalette = Palette<char>;
@@ -85,16 +87,14 @@ impl TerminalPalette {
         self.terminal[self.mode.adjusted_index(index, i)].to_owned()
     }
 
-    pub fn apply(&self, image_data: RawGrid) -> Grid<String> {
-        image_data
-            .iter()
-            .map(|row| {
-                row.iter()
-                    .enumerate()
-                    .map(|(i, index)| self.adjusted_get(*index as usize, i).to_owned())
-                    .collect::<Vec<String>>()
-            })
-            .collect::<Grid<String>>()
+    pub fn apply(&self, image_data: &RawGrid) -> Grid<String> {
+        let width = image_data.width();
+        let cells = image_data
+            .pixels()
+            .enumerate()
+            .map(|(i, index)| self.adjusted_get(*index as usize, i % width))
+            .collect();
+        Grid::from_flat(cells, width)
     }
 }
 
@@ -102,13 +102,22 @@ pub fn disable_wrapping(string: String) -> String {
     format!("{}{}{}", DISABLEWRAPPING, string, ENABLEWRAPPING)
 }
 
-pub fn to_string(grid: Grid<String>) -> String {
-    grid.iter()
+pub fn to_string(grid: &Grid<String>) -> String {
+    grid.rows()
         .map(|row| row.join(""))
         .collect::<Vec<_>>()
         .join("\n")
 }
 
+pub fn format_scores<T: Display>(scores: &[T], count: usize) -> String {
+    scores
+        .iter()
+        .take(count)
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 pub fn ansi_codes(co: u8, ch: &char) -> String {
     format!("{}{}m{}{}", ANSIOPEN, co, ch, ANSIRESET)
 }
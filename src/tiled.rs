@@ -0,0 +1,321 @@
+//! [Tiled](https://www.mapeditor.org) files, so decoded maps can be edited and written back.
+//! A tileset is exported as a PNG atlas with a `.tsx` describing it, and a map as a `.tmx`
+//! whose layer is CSV tile numbers (Tiled's gids, which start at the tileset's `firstgid`, 1).
+//! Reading a `.tmx` back gives the map in its raw format again
+//! https://doc.mapeditor.org/en/stable/reference/tmx-map-format/
+
+use std::borrow::Cow;
+use std::io;
+use std::path::Path;
+
+use quick_xml::events::{BytesDecl, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+
+use crate::image::{self, Image};
+use crate::tilemap::{Flip, IndexSize, Tilemap, Tileset};
+use crate::{png, ColorPalette, Grid, RawGrid};
+
+pub const TSX_EXTENSION: &str = "tsx";
+pub const TMX_EXTENSION: &str = "tmx";
+const VERSION: &str = "1.10";
+const FIRST_GID: u32 = 1;
+//the top bits of a gid are flip and rotation flags, which a raw map has no room for
+const FLIP_FLAGS: u32 = 0xF000_0000;
+const FLIPPED_HORIZONTALLY: u32 = 0x8000_0000;
+const FLIPPED_VERTICALLY: u32 = 0x4000_0000;
+
+/// The tiles laid out side by side, as [image::tile] would, for Tiled to cut up again
+pub fn atlas(tileset: &Tileset) -> RawGrid {
+    image::tile(&tileset.strip(), tileset.tile_height.max(1))
+}
+
+pub fn tsx(name: &str, tileset: &Tileset, atlas_source: &str, atlas: &RawGrid) -> String {
+    let columns = atlas.width() / tileset.tile_width.max(1);
+    write_xml(|writer| {
+        writer
+            .create_element("tileset")
+            .with_attributes([
+                ("version", VERSION),
+                ("name", name),
+                ("tilewidth", tileset.tile_width.to_string().as_str()),
+                ("tileheight", tileset.tile_height.to_string().as_str()),
+                ("tilecount", tileset.len().to_string().as_str()),
+                ("columns", columns.to_string().as_str()),
+            ])
+            .write_inner_content(|writer| {
+                writer
+                    .create_element("image")
+                    .with_attributes([
+                        ("source", atlas_source),
+                        ("width", atlas.width().to_string().as_str()),
+                        ("height", atlas.height().to_string().as_str()),
+                    ])
+                    .write_empty()?;
+                Ok(())
+            })?;
+        Ok(())
+    })
+}
+
+/// Flips, as [Tileset::deduplicate] finds, are kept in the gids' flag bits
+pub fn tmx(
+    map: &Tilemap,
+    flips: Option<&Grid<Flip>>,
+    tileset: &Tileset,
+    tsx_source: &str,
+) -> String {
+    let gid = |x: usize, y: usize, index: u16| {
+        let flip = flips
+            .and_then(|flips| flips.get(x, y))
+            .copied()
+            .unwrap_or_default();
+        (index as u32 + FIRST_GID)
+            | if flip.horizontal {
+                FLIPPED_HORIZONTALLY
+            } else {
+                0
+            }
+            | if flip.vertical { FLIPPED_VERTICALLY } else { 0 }
+    };
+    let csv = map
+        .indices
+        .rows()
+        .enumerate()
+        .map(|(y, row)| {
+            row.iter()
+                .enumerate()
+                .map(|(x, &i)| gid(x, y, i).to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .collect::<Vec<_>>()
+        .join(",\n");
+    let (width, height) = (map.width().to_string(), map.height().to_string());
+    write_xml(|writer| {
+        writer
+            .create_element("map")
+            .with_attributes([
+                ("version", VERSION),
+                ("orientation", "orthogonal"),
+                ("renderorder", "right-down"),
+                ("width", width.as_str()),
+                ("height", height.as_str()),
+                ("tilewidth", tileset.tile_width.to_string().as_str()),
+                ("tileheight", tileset.tile_height.to_string().as_str()),
+                ("infinite", "0"),
+                ("nextlayerid", "2"),
+                ("nextobjectid", "1"),
+            ])
+            .write_inner_content(|writer| {
+                writer
+                    .create_element("tileset")
+                    .with_attributes([
+                        ("firstgid", FIRST_GID.to_string().as_str()),
+                        ("source", tsx_source),
+                    ])
+                    .write_empty()?;
+                writer
+                    .create_element("layer")
+                    .with_attributes([
+                        ("id", "1"),
+                        ("name", "map"),
+                        ("width", width.as_str()),
+                        ("height", height.as_str()),
+                    ])
+                    .write_inner_content(|writer| {
+                        writer
+                            .create_element("data")
+                            .with_attribute(("encoding", "csv"))
+                            .write_text_content(BytesText::new(&format!("\n{}\n", csv)))?;
+                        Ok(())
+                    })?;
+                Ok(())
+            })?;
+        Ok(())
+    })
+}
+
+/// Writes `<name>.png` and `<name>.tsx` to the directory, returning the `.tsx` file name
+pub fn export_tileset(
+    dir: &Path,
+    name: &str,
+    tileset: &Tileset,
+    palette: &ColorPalette,
+) -> Result<String, String> {
+    let atlas = atlas(tileset);
+    let (png_name, tsx_name) = (
+        format!("{}.png", name),
+        format!("{}.{}", name, TSX_EXTENSION),
+    );
+    png::save(
+        dir.join(&png_name),
+        &Image {
+            palette: Some(palette.clone()),
+            ..Image::new(atlas.clone())
+        },
+    )
+    .map_err(|e| e.to_string())?;
+    std::fs::write(dir.join(&tsx_name), tsx(name, tileset, &png_name, &atlas))
+        .map_err(|e| e.to_string())?;
+    Ok(tsx_name)
+}
+
+/// The first tile layer of a `.tmx` with CSV data, as the indices of its first tileset
+pub fn read_tmx(xml: &str, index_size: IndexSize) -> Result<Tilemap, String> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+    let (mut first_gid, mut size, mut in_data) = (None, None, false);
+    loop {
+        match reader.read_event().map_err(|e| e.to_string())? {
+            Event::Start(e) | Event::Empty(e) => match e.name().as_ref() {
+                b"tileset" if first_gid.is_none() => first_gid = Some(attribute(&e, "firstgid")?),
+                b"layer" if size.is_none() => {
+                    size = Some((
+                        attribute(&e, "width")? as usize,
+                        attribute(&e, "height")? as usize,
+                    ))
+                }
+                b"data" if size.is_some() => {
+                    let encoding = e
+                        .try_get_attribute("encoding")
+                        .map_err(|e| e.to_string())?
+                        .map(|a| a.unescape_value().map(Cow::into_owned))
+                        .transpose()
+                        .map_err(|e| e.to_string())?;
+                    if encoding.as_deref() != Some("csv") {
+                        return Err(
+                            "only CSV layer data can be read, change the layer format in Tiled"
+                                .to_owned(),
+                        );
+                    }
+                    in_data = true;
+                }
+                _ => (),
+            },
+            Event::Text(text) if in_data => {
+                let csv = text.unescape().map_err(|e| e.to_string())?;
+                let (width, height) = size
+                    .filter(|&(w, _)| w > 0)
+                    .ok_or("the layer has no width")?;
+                let indices = csv
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|gid| !gid.is_empty())
+                    .enumerate()
+                    .map(|(i, gid)| {
+                        index(gid, first_gid.unwrap_or(FIRST_GID), index_size)
+                            .map_err(|e| format!("{} at {},{}", e, i % width, i / width))
+                    })
+                    .collect::<Result<Vec<u16>, String>>()?;
+                if !indices.len().is_multiple_of(width) {
+                    return Err("the layer's data doesn't fill its width".to_owned());
+                }
+                if indices.len() / width != height {
+                    return Err(format!(
+                        "the layer is {} rows high but its data has {}",
+                        height,
+                        indices.len() / width
+                    ));
+                }
+                return Ok(Tilemap {
+                    indices: Grid::from_flat(indices, width),
+                    index_size,
+                });
+            }
+            Event::Eof => return Err("no tile layer with data".to_owned()),
+            _ => (),
+        }
+    }
+}
+
+fn index(gid: &str, first_gid: u32, index_size: IndexSize) -> Result<u16, String> {
+    let gid: u32 = gid.parse().map_err(|_| format!("bad tile {}", gid))?;
+    if gid & FLIP_FLAGS != 0 {
+        return Err("flipped tiles can't be stored".to_owned());
+    }
+    let max = match index_size {
+        IndexSize::U8 => u8::MAX as u32,
+        IndexSize::U16 => u16::MAX as u32,
+    };
+    gid.checked_sub(first_gid)
+        .ok_or_else(|| "empty tiles can't be stored".to_owned())?
+        .try_into()
+        .ok()
+        .filter(|&i: &u16| i as u32 <= max)
+        .ok_or_else(|| format!("tile {} doesn't fit {}", gid - first_gid, index_size))
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Result<u32, String> {
+    e.try_get_attribute(name)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("missing {}", name))?
+        .unescape_value()
+        .map_err(|e| e.to_string())?
+        .parse()
+        .map_err(|_| format!("bad {}", name))
+}
+
+fn write_xml(content: impl FnOnce(&mut Writer<Vec<u8>>) -> io::Result<()>) -> String {
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 1);
+    //writing to memory can't fail
+    writer
+        .write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))
+        .and_then(|_| content(&mut writer))
+        .expect("writing xml to memory");
+    String::from_utf8(writer.into_inner()).expect("xml from strings") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tileset() -> Tileset {
+        Tileset::from_grid(&Grid::from_flat((0..48).map(|i| i / 8).collect(), 4), 4, 2)
+    }
+
+    #[test]
+    fn exports() {
+        let tileset = tileset();
+        let atlas = atlas(&tileset);
+        assert_eq!((atlas.width(), atlas.height()), (24, 2));
+        let tsx = tsx("tiles", &tileset, "tiles.png", &atlas);
+        assert!(tsx.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<tileset"));
+        assert!(tsx.contains("tilecount=\"6\" columns=\"6\""));
+        assert!(tsx.contains("<image source=\"tiles.png\" width=\"24\" height=\"2\"/>"));
+
+        let map = Tilemap::read(&[5, 0, 1, 2], 2, IndexSize::U8).unwrap();
+        let tmx = tmx(&map, None, &tileset, "tiles.tsx");
+        assert!(tmx.contains("<tileset firstgid=\"1\" source=\"tiles.tsx\"/>"));
+        assert!(tmx.contains("6,1,\n2,3"));
+        assert_eq!(read_tmx(&tmx, IndexSize::U8), Ok(map.clone()));
+
+        let mut flips = Grid::new(2, 2);
+        flips[(0, 1)] = Flip::new(true, true);
+        let flipped = super::tmx(&map, Some(&flips), &tileset, "tiles.tsx");
+        assert!(flipped.contains("6,1,\n3221225474,3"));
+    }
+
+    #[test]
+    fn imports() {
+        let tmx = |gids: &str| {
+            format!(
+                r#"<map><tileset firstgid="3" source="a.tsx"/><layer width="2" height="1"><data encoding="csv">{}</data></layer></map>"#,
+                gids
+            )
+        };
+        let map = read_tmx(&tmx("3, 300"), IndexSize::U16).unwrap();
+        assert_eq!(map.to_bytes(), vec![0, 0, 41, 1]);
+        assert!(read_tmx(&tmx("3, 300"), IndexSize::U8).is_err());
+        assert!(read_tmx(&tmx("0, 3"), IndexSize::U8).is_err());
+        assert!(read_tmx(&tmx("3, 2147483651"), IndexSize::U8).is_err());
+        assert!(read_tmx(&tmx("3"), IndexSize::U8).is_err());
+        assert!(read_tmx(&tmx("3, 3,\n3, 3"), IndexSize::U8)
+            .unwrap_err()
+            .contains("1 rows high"));
+        assert!(read_tmx(
+            r#"<map><layer width="1" height="1"><data encoding="base64">AQAAAA==</data></layer></map>"#,
+            IndexSize::U8
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,390 @@
+//! Maps built from a tileset: a map file is a row-major run of tile indices, one or two bytes
+//! (little endian) each, which are drawn with the tiles they index into one big image
+//! https://moddingwiki.shikadi.net/wiki/Tilemap
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{Grid, RawGrid};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexSize {
+    #[default]
+    U8,
+    U16,
+}
+
+impl IndexSize {
+    pub fn type_str(str: &str) -> Result<IndexSize, String> {
+        match str {
+            "u8" => Ok(IndexSize::U8),
+            "u16" => Ok(IndexSize::U16),
+            _ => Err(format!("unknown tile index size: {}", str)),
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+        }
+    }
+}
+
+impl fmt::Display for IndexSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::U8 => write!(f, "u8"),
+            Self::U16 => write!(f, "u16"),
+        }
+    }
+}
+
+/// How a map cell's tile is mirrored when drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flip {
+    pub horizontal: bool,
+    pub vertical: bool,
+}
+
+impl Flip {
+    pub const ALL: [Flip; 4] = [
+        Flip::new(false, false),
+        Flip::new(true, false),
+        Flip::new(false, true),
+        Flip::new(true, true),
+    ];
+
+    pub const fn new(horizontal: bool, vertical: bool) -> Self {
+        Self {
+            horizontal,
+            vertical,
+        }
+    }
+
+    pub fn is_flipped(&self) -> bool {
+        self.horizontal || self.vertical
+    }
+
+    pub fn apply(&self, tile: &RawGrid) -> RawGrid {
+        let mut rows = tile.to_vecs();
+        if self.horizontal {
+            rows.iter_mut().for_each(|row| row.reverse());
+        }
+        if self.vertical {
+            rows.reverse();
+        }
+        Grid::from_flat(rows.concat(), tile.width())
+    }
+}
+
+/// A map rebuilt from an image, and the tileset it draws with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deduplicated {
+    pub tileset: Tileset,
+    pub map: Tilemap,
+    pub flips: Grid<Flip>,
+    /// How many tiles came from the tileset deduplicating started with
+    pub known: usize,
+}
+
+/// Same sized tiles, numbered in the order a map indexes them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tileset {
+    pub tiles: Vec<RawGrid>,
+    pub tile_width: usize,
+    pub tile_height: usize,
+}
+
+impl Tileset {
+    pub fn new(tile_width: usize, tile_height: usize) -> Self {
+        Self {
+            tiles: vec![],
+            tile_width,
+            tile_height,
+        }
+    }
+
+    /// Cuts whole tiles out of the grid, left to right then top to bottom. A strip of tiles as
+    /// decoded (one tile wide) and a sheet of them laid out side by side both work
+    pub fn from_grid(grid: &RawGrid, tile_width: usize, tile_height: usize) -> Self {
+        let columns = grid.width() / tile_width.max(1);
+        let rows = grid.height() / tile_height.max(1);
+        let tiles = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .map(|(column, row)| {
+                grid.view(
+                    column * tile_width,
+                    row * tile_height,
+                    tile_width,
+                    tile_height,
+                )
+                .to_grid()
+            })
+            .collect();
+        Self {
+            tiles,
+            tile_width,
+            tile_height,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    pub fn tile(&self, index: usize) -> Option<&RawGrid> {
+        self.tiles.get(index)
+    }
+
+    /// The tiles stacked one above the other, as tile files store them
+    pub fn strip(&self) -> RawGrid {
+        let mut strip = Grid::new(self.tile_width, self.len() * self.tile_height);
+        for (i, tile) in self.tiles.iter().enumerate() {
+            strip.blit(&tile.as_view(), 0, i * self.tile_height);
+        }
+        strip
+    }
+
+    /// Cuts the image into tiles and keeps one of each, after this tileset's own tiles so a known
+    /// tileset keeps its numbering. With `flips`, a mirror image of a kept tile is drawn flipped
+    /// rather than kept. A partial last row or column of tiles is padded with color 0
+    pub fn deduplicate(&self, grid: &RawGrid, flips: bool) -> Result<Deduplicated, String> {
+        let (tile_width, tile_height) = (self.tile_width.max(1), self.tile_height.max(1));
+        let (columns, rows) = (
+            grid.width().div_ceil(tile_width),
+            grid.height().div_ceil(tile_height),
+        );
+        let mut padded = Grid::new(columns * tile_width, rows * tile_height);
+        padded.blit(&grid.as_view(), 0, 0);
+
+        let variants = if flips {
+            &Flip::ALL[..]
+        } else {
+            &Flip::ALL[..1]
+        };
+        //every way a kept tile can be drawn, by its pixels. The first tile to look a way wins
+        let mut seen: HashMap<Vec<u8>, (usize, Flip)> = HashMap::new();
+        let remember = |seen: &mut HashMap<Vec<u8>, (usize, Flip)>, tile: &RawGrid, index| {
+            for flip in variants {
+                seen.entry(flip.apply(tile).into_flat())
+                    .or_insert((index, *flip));
+            }
+        };
+        let mut tileset = Tileset::new(tile_width, tile_height);
+        for tile in &self.tiles {
+            remember(&mut seen, tile, tileset.len());
+            tileset.tiles.push(tile.clone());
+        }
+
+        let mut cells = vec![];
+        for row in 0..rows {
+            for column in 0..columns {
+                let tile = padded
+                    .view(
+                        column * tile_width,
+                        row * tile_height,
+                        tile_width,
+                        tile_height,
+                    )
+                    .to_grid();
+                let key: Vec<u8> = tile.pixels().copied().collect();
+                let cell = match seen.get(&key) {
+                    Some(&cell) => cell,
+                    None => {
+                        remember(&mut seen, &tile, tileset.len());
+                        tileset.tiles.push(tile);
+                        (tileset.len() - 1, Flip::default())
+                    }
+                };
+                cells.push(cell);
+            }
+        }
+        if tileset.len() > u16::MAX as usize + 1 {
+            return Err(format!(
+                "{} distinct tiles are too many to index",
+                tileset.len()
+            ));
+        }
+        let index_size = if tileset.len() > u8::MAX as usize + 1 {
+            IndexSize::U16
+        } else {
+            IndexSize::U8
+        };
+        Ok(Deduplicated {
+            map: Tilemap {
+                indices: Grid::from_flat(cells.iter().map(|&(i, _)| i as u16).collect(), columns),
+                index_size,
+            },
+            flips: Grid::from_flat(cells.iter().map(|&(_, flip)| flip).collect(), columns),
+            known: self.len(),
+            tileset,
+        })
+    }
+}
+
+/// Tile indices, `width` tiles to a row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tilemap {
+    pub indices: Grid<u16>,
+    pub index_size: IndexSize,
+}
+
+impl Tilemap {
+    pub fn read(bytes: &[u8], width: usize, index_size: IndexSize) -> Result<Self, String> {
+        if width == 0 {
+            return Err("map width must be at least 1".to_owned());
+        }
+        if !bytes.len().is_multiple_of(width * index_size.bytes()) {
+            return Err(format!(
+                "{} bytes isn't whole rows of {} {} tiles",
+                bytes.len(),
+                width,
+                index_size
+            ));
+        }
+        let indices = match index_size {
+            IndexSize::U8 => bytes.iter().map(|&b| b as u16).collect(),
+            IndexSize::U16 => bytes
+                .chunks(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect(),
+        };
+        Ok(Self {
+            indices: Grid::from_flat(indices, width),
+            index_size,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.index_size {
+            IndexSize::U8 => self.indices.pixels().map(|&i| i as u8).collect(),
+            IndexSize::U16 => self
+                .indices
+                .pixels()
+                .flat_map(|i| i.to_le_bytes())
+                .collect(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.indices.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.indices.height()
+    }
+
+    pub fn render(&self, tileset: &Tileset) -> Result<RawGrid, String> {
+        self.render_flipped(tileset, &Grid::new(self.width(), self.height()))
+    }
+
+    /// Draws each cell's tile mirrored as its flip says
+    pub fn render_flipped(&self, tileset: &Tileset, flips: &Grid<Flip>) -> Result<RawGrid, String> {
+        let (tile_width, tile_height) = (tileset.tile_width, tileset.tile_height);
+        let mut map = Grid::new(self.width() * tile_width, self.height() * tile_height);
+        for (y, row) in self.indices.rows().enumerate() {
+            for (x, &index) in row.iter().enumerate() {
+                let tile = tileset.tile(index as usize).ok_or(format!(
+                    "tile {} at {},{} is past the {} tiles in the tileset",
+                    index,
+                    x,
+                    y,
+                    tileset.len()
+                ))?;
+                match flips.get(x, y).filter(|flip| flip.is_flipped()) {
+                    Some(flip) => {
+                        map.blit(&flip.apply(tile).as_view(), x * tile_width, y * tile_height)
+                    }
+                    None => map.blit(&tile.as_view(), x * tile_width, y * tile_height),
+                }
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tileset() -> Tileset {
+        //four 2x2 tiles, each filled with its index, stacked as decoded
+        let strip = Grid::from_flat((0..16).map(|i| i / 4).collect(), 2);
+        Tileset::from_grid(&strip, 2, 2)
+    }
+
+    #[test]
+    fn slices_tiles() {
+        let tiles = tileset();
+        assert_eq!(tiles.len(), 4);
+        assert_eq!(tiles.tile(3), Some(&Grid::filled(2, 2, 3)));
+        //the same tiles laid out in a sheet, with a partial tile left over
+        let sheet = Grid::from_flat(
+            [[0, 0, 1, 1, 9], [0, 0, 1, 1, 9], [2, 2, 3, 3, 9]].concat(),
+            5,
+        );
+        let sliced = Tileset::from_grid(&sheet, 2, 1);
+        assert_eq!(sliced.len(), 6);
+        assert_eq!(sliced.tile(5), Some(&Grid::filled(2, 1, 3)));
+        assert_eq!(Tileset::from_grid(&tiles.strip(), 2, 2), tiles);
+    }
+
+    #[test]
+    fn renders() {
+        let map = Tilemap::read(&[3, 0, 1, 0, 2, 0], 3, IndexSize::U16).unwrap();
+        assert_eq!((map.width(), map.height()), (3, 1));
+        let grid = map.render(&tileset()).unwrap();
+        assert_eq!(grid.to_vecs(), vec![vec![3, 3, 1, 1, 2, 2]; 2]);
+        assert_eq!(map.to_bytes(), vec![3, 0, 1, 0, 2, 0]);
+
+        let bytes = Tilemap::read(&[0, 1, 2, 3], 2, IndexSize::U8).unwrap();
+        assert_eq!(bytes.render(&tileset()).unwrap().row(3), &[2, 2, 3, 3]);
+    }
+
+    #[test]
+    fn deduplicates() {
+        //a 2x2 tile, its mirror image, and a blank one, in a 6x3 image
+        let image = Grid::from_flat(
+            [[1, 2, 2, 1, 0, 0], [3, 3, 3, 3, 0, 0], [1, 2, 1, 2, 2, 1]].concat(),
+            6,
+        );
+        let unflipped = Tileset::new(2, 2).deduplicate(&image, false).unwrap();
+        assert_eq!(unflipped.tileset.len(), 5);
+        assert_eq!(
+            unflipped.map.indices.to_vecs(),
+            vec![vec![0, 1, 2], vec![3, 3, 4]]
+        );
+        assert_eq!(unflipped.map.index_size, IndexSize::U8);
+
+        let flipped = Tileset::new(2, 2).deduplicate(&image, true).unwrap();
+        assert_eq!(flipped.tileset.len(), 3);
+        assert_eq!(flipped.map.indices.row(0), &[0, 0, 1]);
+        assert_eq!(flipped.flips.get(1, 0), Some(&Flip::new(true, false)));
+        //the padding row is cut off again
+        let rendered = flipped
+            .map
+            .render_flipped(&flipped.tileset, &flipped.flips)
+            .unwrap();
+        assert_eq!(rendered.view(0, 0, 6, 3).to_grid(), image);
+
+        //a known tileset keeps its numbers
+        let known = Tileset::from_grid(&Grid::filled(2, 2, 0), 2, 2);
+        let matched = known.deduplicate(&image, true).unwrap();
+        assert_eq!(matched.known, 1);
+        assert_eq!(matched.map.indices.row(0), &[1, 1, 0]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Tilemap::read(&[0, 1, 2], 2, IndexSize::U8).is_err());
+        assert!(Tilemap::read(&[0, 1, 2], 1, IndexSize::U16).is_err());
+        assert!(Tilemap::read(&[], 0, IndexSize::U8).is_err());
+        let map = Tilemap::read(&[4], 1, IndexSize::U8).unwrap();
+        assert!(map.render(&tileset()).is_err());
+    }
+}
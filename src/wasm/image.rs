@@ -1,11 +1,10 @@
 use base64::{engine::general_purpose::STANDARD, Engine};
 
-use web_sys::HtmlInputElement;
+use web_sys::{HtmlInputElement, HtmlSelectElement};
 use yew::{html, Callback, Component, Context, Event, Html, Properties, SubmitEvent, TargetCast};
 
-use crate::color::palette::palette_from_abbr;
 use crate::parser::ParserType;
-use crate::{file_data, png};
+use crate::{file_data, png, registry};
 
 use crate::wasm::FileUpload;
 
@@ -16,27 +15,30 @@ pub struct Props {
 
 pub struct ImageComponent {
     width: usize,
+    parser: ParserType,
 }
 
 pub enum Msg {
     Width(usize),
+    Parser(ParserType),
 }
 
 impl ImageComponent {
-    pub fn src(&self, file: &FileUpload) -> String {
+    pub fn src(&self, file: &FileUpload) -> Result<String, String> {
         let data = if file.mime_type.contains("image") {
             file.data.clone()
         } else {
             let file_data = file_data::Raw::new(&file.data);
-            let parser = ParserType::CGA;
-            let image = file_data.parse(parser, self.width);
-            let palette = palette_from_abbr("cga0");
+            let image = file_data.parse(self.parser, self.width)?;
             let mut bytes: Vec<u8> = Vec::new();
 
-            let _ = png::write_to(&mut bytes, image.data(), palette.clone());
+            let _ = png::write_to(&mut bytes, image.data(), &image.palette());
             bytes
         };
-        format!("data:application/png;base64,{}", STANDARD.encode(data))
+        Ok(format!(
+            "data:application/png;base64,{}",
+            STANDARD.encode(data)
+        ))
     }
 
     pub fn previews(&self, ctx: &Context<Self>, file: &FileUpload) -> Html {
@@ -48,10 +50,9 @@ impl ImageComponent {
                 .previews()
                 .iter()
                 .map(|p| {
-                    let palette = palette_from_abbr("cga0");
                     let mut bytes: Vec<u8> = Vec::new();
 
-                    let _ = png::write_to(&mut bytes, p.data(), palette.clone());
+                    let _ = png::write_to(&mut bytes, p.data(), &p.palette());
                     let src = format!("data:application/png;base64,{}", STANDARD.encode(bytes));
                     let width = p.width().clone();
                     html! {
@@ -73,7 +74,10 @@ impl Component for ImageComponent {
     type Properties = Props;
 
     fn create(_ctx: &Context<Self>) -> Self {
-        Self { width: 320 }
+        Self {
+            width: 320,
+            parser: ParserType::CGA,
+        }
     }
 
     fn update(&mut self, _ctx: &Context<Self>, msg: Self::Message) -> bool {
@@ -81,6 +85,9 @@ impl Component for ImageComponent {
             Msg::Width(w) => {
                 self.width = w;
             }
+            Msg::Parser(parser) => {
+                self.parser = parser;
+            }
         }
         true
     }
@@ -93,18 +100,37 @@ impl Component for ImageComponent {
             let input: HtmlInputElement = e.target_unchecked_into();
             Msg::Width(input.value().parse().expect("fail to parse width"))
         });
+        let onparser = ctx.link().callback(|e: Event| {
+            let select: HtmlSelectElement = e.target_unchecked_into();
+            Msg::Parser(ParserType::type_str(&select.value()).expect("parser from the list"))
+        });
+        //only the headerless parsers, the others take their size from the file rather than the width
+        let parsers: Html = registry::raw()
+            .iter()
+            .map(|parser| {
+                html! {
+                    <option value={parser.name()} selected={*parser == self.parser}>{parser.name()}</option>
+                }
+            })
+            .collect();
         let file = &ctx.props().file;
+        let preview = match self.src(file) {
+            Ok(src) => html! { <img src={ src } /> },
+            Err(e) => html! { <p class="preview-error">{ e }</p> },
+        };
 
         html! {
             <>
                 <div class="preview-tile">
                     <div class=".preview-media">
                         <p class="preview-name">{ file.name.to_string() }</p>
-                        <img src={ self.src(file) } />
+                        { preview }
                     </div>
                     <form onsubmit={noop}>
                             <label for="width">{"Width"}</label>
                             <input name="width" type="number" value={self.width.to_string()} {onchange} />
+                            <label for="parser">{"Parser"}</label>
+                            <select name="parser" onchange={onparser}>{parsers}</select>
                     </form>
                 </div>
                 <div class="preview-row">